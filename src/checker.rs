@@ -0,0 +1,825 @@
+use std::{collections::{HashMap, HashSet}, error::Error, fmt::Display};
+
+use crate::{lexer::{Token, TokenPosition, TokenType}, types::{Keyword, Operation, Signature, Value}, vm::Mismatch};
+
+/// What the checker knows about a value's type
+#[derive(Debug, Clone, PartialEq)]
+enum Ty {
+    Int,
+    Float,
+    /// An int or a float
+    Number,
+    Bool,
+    Str,
+    /// A block, with its stack effect if it could be worked out
+    Block(Option<Effect>),
+    Function(String),
+    Tag(String),
+    /// The nth value that the code takes from below its own stack, counting from the top
+    Input(usize),
+    Unknown,
+}
+
+impl Ty {
+    fn name(&self) -> &str {
+        match self {
+            Ty::Int => "int",
+            Ty::Float => "float",
+            Ty::Number => "number",
+            Ty::Bool => "bool",
+            Ty::Str => "string",
+            Ty::Block(_) => "block",
+            Ty::Function(_) => "function",
+            Ty::Tag(t) => t,
+            Ty::Input(_) | Ty::Unknown => "any",
+        }
+    }
+
+    /// The type a declared parameter needs, `Unknown` for a label
+    fn from_param(param: &str) -> Ty {
+        match Signature::param_type(param) {
+            Some("int") => Ty::Int,
+            Some("float") => Ty::Float,
+            Some("number") => Ty::Number,
+            Some("string") => Ty::Str,
+            Some("bool") => Ty::Bool,
+            Some("block") => Ty::Block(None),
+            Some("function") => Ty::Function(String::new()),
+            _ => Ty::Unknown,
+        }
+    }
+}
+
+/// How well a type fits the type something needs
+#[derive(PartialEq)]
+enum Fit {
+    Yes,
+    Maybe,
+    No,
+}
+
+/// Whether a value of type `ty` can be used where `wanted` is needed.
+/// `Unknown` is wanted for any value, and `Block(None)` for anything that can be run.
+fn fits(ty: &Ty, wanted: &Ty) -> Fit {
+    match (ty, wanted) {
+        (Ty::Unknown | Ty::Input(_), _) => Fit::Maybe,
+        (_, Ty::Unknown) => Fit::Yes,
+        (Ty::Int | Ty::Float | Ty::Number, Ty::Number) => Fit::Yes,
+        (Ty::Number, Ty::Int | Ty::Float) => Fit::Maybe,
+        (Ty::Block(_) | Ty::Function(_), Ty::Block(_)) => Fit::Yes,
+        (Ty::Tag(t1), Ty::Tag(t2)) => if t1 == t2 {Fit::Yes} else {Fit::No},
+        (ty, wanted) if std::mem::discriminant(ty) == std::mem::discriminant(wanted) => Fit::Yes,
+        _ => Fit::No,
+    }
+}
+
+/// How a function or block changes the stack
+#[derive(Debug, Clone, PartialEq)]
+pub struct Effect {
+    /// What each value taken from the stack needs to be, top first
+    inputs: Vec<Ty>,
+    /// The values left on the stack, bottom first
+    outputs: Vec<Ty>,
+}
+
+impl From<&Signature> for Effect {
+    fn from(signature: &Signature) -> Self {
+        let inputs = signature.inputs.iter().rev().map(|param| Ty::from_param(param)).collect();
+        let outputs = signature.outputs.iter().map(|param| match Ty::from_param(param) {
+            //A label that names an input is that input
+            Ty::Unknown => match signature.inputs.iter().rposition(|input| input == param) {
+                Some(i) => Ty::Input(signature.inputs.len() - 1 - i),
+                None => Ty::Unknown,
+            },
+            ty => ty,
+        }).collect();
+        Effect {inputs, outputs}
+    }
+}
+
+/// Shows the effect in the usual `( inputs -- outputs )` notation, bottom of the stack first.
+/// Inputs of any type are named with letters, so outputs can show which input they are.
+impl Display for Effect {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let count = self.inputs.len();
+        let label = |i: usize| match &self.inputs[i] {
+            Ty::Unknown => ((b'a' + ((count - 1 - i) % 26) as u8) as char).to_string(),
+            ty => ty.name().to_string(),
+        };
+        let inputs: Vec<String> = (0..count).rev().map(label).collect();
+        let outputs: Vec<String> = self.outputs.iter().map(|ty| match ty {
+            Ty::Input(i) => label(*i),
+            ty => ty.name().to_string(),
+        }).collect();
+        write!(f, "(")?;
+        for input in &inputs {
+            write!(f, " {}", input)?;
+        }
+        write!(f, " --")?;
+        for output in &outputs {
+            write!(f, " {}", output)?;
+        }
+        write!(f, " )")
+    }
+}
+
+#[derive(Debug)]
+pub enum CheckError {
+    /// Code that starts on an empty stack needs more values than there are
+    StackUnderflow(TokenPosition, String, usize, usize),
+    /// An operator or keyword is given values it can't use
+    InvalidValues(TokenPosition, String, Mismatch),
+    /// A function or block is given an input of the wrong type
+    InvalidInput(TokenPosition, String, usize, String, (String, TokenPosition)),
+    /// A loop's body leaves something other than a bool on top
+    LoopConditionNotBool(TokenPosition, String),
+    /// A loop's body changes how many values are on the stack, apart from the bool it leaves
+    LoopUnbalanced(TokenPosition, usize, usize),
+    /// A function's body doesn't leave what its declared stack effect says it does
+    EffectMismatch(TokenPosition, String, String, String),
+}
+
+impl CheckError {
+    pub fn position(&self) -> TokenPosition {
+        match self {
+            CheckError::StackUnderflow(pos, _, _, _) => *pos,
+            CheckError::InvalidValues(pos, _, _) => *pos,
+            CheckError::InvalidInput(pos, _, _, _, _) => *pos,
+            CheckError::LoopConditionNotBool(pos, _) => *pos,
+            CheckError::LoopUnbalanced(pos, _, _) => *pos,
+            CheckError::EffectMismatch(pos, _, _, _) => *pos,
+        }
+    }
+
+    /// Warnings are about code that might be intended, errors will always fail when run
+    pub fn is_warning(&self) -> bool {
+        matches!(self, CheckError::LoopUnbalanced(_, _, _))
+    }
+
+    /// What kind of error this is, shown before its code
+    pub fn kind(&self) -> &'static str {
+        if self.is_warning() {"Check warning"} else {"Check error"}
+    }
+
+    /// The stable code for this error, listed in the README
+    pub fn code(&self) -> &'static str {
+        match self {
+            CheckError::StackUnderflow(_, _, _, _) => "E0401",
+            CheckError::InvalidValues(_, _, _) => "E0402",
+            CheckError::InvalidInput(_, _, _, _, _) => "E0403",
+            CheckError::LoopConditionNotBool(_, _) => "E0404",
+            CheckError::LoopUnbalanced(_, _, _) => "E0405",
+            CheckError::EffectMismatch(_, _, _, _) => "E0406",
+        }
+    }
+
+    /// The error's description, without its kind or position
+    pub fn message(&self) -> String {
+        match self {
+            Self::StackUnderflow(_, culprit, needed, available) =>
+                format!("{} needs {}, but the stack only has {} here", culprit, plural(*needed), available),
+            Self::InvalidValues(_, culprit, mismatch) =>
+                format!("{} {}", culprit, mismatch),
+            Self::InvalidInput(_, culprit, index, expected, (found, _)) =>
+                format!("{} expected {} as input {} from the top, found {}", culprit, expected, index + 1, found),
+            Self::LoopConditionNotBool(_, found) =>
+                format!("The `loop` body should leave a bool on top, but it leaves {}", found),
+            Self::LoopUnbalanced(_, takes, leaves) =>
+                format!("The `loop` body takes {} and leaves {}, so the stack changes size every cycle. It should leave {}, one more for the condition.", plural(*takes), plural(*leaves), plural(takes + 1)),
+            Self::EffectMismatch(_, name, expected, found) =>
+                format!("`{}` is declared to leave {}, but its body leaves {}", name, expected, found),
+        }
+    }
+
+    /// The values that caused the error, with where each one was pushed
+    pub fn culprits(&self) -> &[(String, TokenPosition)] {
+        match self {
+            Self::InvalidValues(_, _, mismatch) => &mismatch.found,
+            Self::InvalidInput(_, _, _, _, found) => std::slice::from_ref(found),
+            _ => &[],
+        }
+    }
+}
+
+impl Display for CheckError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let pos = self.position();
+        write!(f, "{}[{}]({}:{}): {}", self.kind(), self.code(), pos.row, pos.col, self.message())
+    }
+}
+
+impl Error for CheckError {}
+
+/// A value on the checker's stack, with where it was pushed
+#[derive(Debug, Clone)]
+struct Val {
+    ty: Ty,
+    pos: TokenPosition,
+}
+
+/// What the checker knows about the stack at one point in the code
+#[derive(Debug, Clone)]
+struct State {
+    /// The values the code has pushed, that are still there
+    stack: Vec<Val>,
+    /// What each value taken from below the code's own stack needs to be, top first
+    inputs: Vec<Ty>,
+    /// Whether the code can take values from below its own stack.
+    /// The top level and tests start on an empty stack, so they can't.
+    open: bool,
+    /// Set once the checker loses track of the stack, after which missing values aren't reported
+    lost: bool,
+}
+
+impl State {
+    fn new(open: bool) -> Self {
+        State {stack: Vec::new(), inputs: Vec::new(), open, lost: false}
+    }
+
+    fn push(&mut self, ty: Ty, pos: TokenPosition) {
+        self.stack.push(Val {ty, pos});
+    }
+
+    fn pop(&mut self, pos: TokenPosition) -> Val {
+        if let Some(val) = self.stack.pop() {
+            return val;
+        }
+        if self.open && !self.lost {
+            self.inputs.push(Ty::Unknown);
+            return Val {ty: Ty::Input(self.inputs.len() - 1), pos};
+        }
+        Val {ty: Ty::Unknown, pos}
+    }
+
+    /// Forgets everything about the stack, for when the checker can't follow what the code does
+    fn lose(&mut self) {
+        self.stack.clear();
+        self.lost = true;
+    }
+
+    /// What is known about a type, looking through inputs
+    fn resolve(&self, ty: &Ty) -> Ty {
+        match ty {
+            Ty::Input(i) if self.inputs[*i] != Ty::Unknown => self.inputs[*i].clone(),
+            ty => ty.clone(),
+        }
+    }
+
+    /// Records that an input has to be a certain type, if nothing more specific is known
+    fn narrow(&mut self, ty: &Ty, wanted: &Ty) {
+        if let Ty::Input(i) = ty && matches!(self.inputs[*i], Ty::Unknown | Ty::Number) && *wanted != Ty::Unknown {
+            self.inputs[*i] = wanted.clone();
+        }
+    }
+
+    /// Takes values from below until `count` inputs have been taken, so two states can be compared
+    fn take_inputs(&mut self, count: usize, pos: TokenPosition) {
+        while self.inputs.len() < count {
+            self.inputs.push(Ty::Unknown);
+            self.stack.insert(0, Val {ty: Ty::Input(self.inputs.len() - 1), pos});
+        }
+    }
+
+    /// The stack effect of the code so far, if the checker kept track of it
+    fn effect(&self) -> Option<Effect> {
+        if self.lost {
+            return None;
+        }
+        Some(Effect {inputs: self.inputs.clone(), outputs: self.stack.iter().map(|val| val.ty.clone()).collect()})
+    }
+}
+
+/// Combines the states of two paths the code could take, eg. the branches of a `gate`
+fn merge(mut a: State, mut b: State, pos: TokenPosition) -> State {
+    if a.lost || b.lost {
+        a.lose();
+        return a;
+    }
+    let count = a.inputs.len().max(b.inputs.len());
+    a.take_inputs(count, pos);
+    b.take_inputs(count, pos);
+    if a.stack.len() != b.stack.len() {
+        a.lose();
+        return a;
+    }
+    for i in 0..a.stack.len() {
+        if a.stack[i].ty != b.stack[i].ty {
+            //An input on one path might be known to be the same type as the value on the other
+            a.stack[i].ty = match (a.resolve(&a.stack[i].ty), b.resolve(&b.stack[i].ty)) {
+                (ty_a, ty_b) if ty_a == ty_b => ty_a,
+                (Ty::Int | Ty::Float | Ty::Number, Ty::Int | Ty::Float | Ty::Number) => Ty::Number,
+                (Ty::Block(_) | Ty::Function(_), Ty::Block(_) | Ty::Function(_)) => Ty::Block(None),
+                _ => Ty::Unknown,
+            };
+        }
+    }
+    for (input_a, input_b) in a.inputs.iter_mut().zip(b.inputs) {
+        if *input_a == Ty::Unknown {
+            *input_a = input_b;
+        }
+    }
+    a
+}
+
+/// Formats a number of values, eg. "1 value" or "2 values"
+fn plural(count: usize) -> String {
+    if count == 1 { "1 value".to_string() } else { format!("{} values", count) }
+}
+
+/// The result of checking stackathon code
+pub struct Report {
+    /// The stack effect of each function that was checked, sorted by name. `None` if it couldn't be worked out.
+    pub effects: Vec<(String, Option<Effect>)>,
+    /// Every problem found, in source order
+    pub errors: Vec<CheckError>,
+}
+
+/// Checks tokens for stack underflows and type errors without running them
+///
+/// Each function's stack effect is worked out from its body. The top level code and tests start on an
+/// empty stack, so taking more values than they have pushed is an error. Values that the checker can't
+/// follow, like the results of `pick` or functions that call themselves, are never reported.
+///
+/// **Arguments:**
+/// * `tokens`: The top level code to check
+/// * `functions`: The function table
+/// * `local`: The functions to check and report problems in. Others, eg. from libraries, are only used for their effects.
+pub fn check(tokens: &[Token], functions: &HashMap<String, Vec<Token>>, local: &HashSet<String>) -> Report {
+    let mut checker = Checker {functions, local, effects: HashMap::new(), in_progress: HashSet::new(), errors: Vec::new()};
+
+    let mut names: Vec<&String> = local.iter().filter(|name| functions.get(*name).is_some_and(|body| !body.is_empty())).collect();
+    names.sort();
+    let mut effects = Vec::new();
+    for name in names {
+        if name.starts_with("test_") {
+            //Tests run on an empty stack, so check them like the top level code
+            checker.run(&functions[name], &mut State::new(false), true);
+        } else {
+            effects.push((name.clone(), checker.effect_of(name)));
+        }
+    }
+    checker.run(tokens, &mut State::new(false), true);
+
+    let mut errors = checker.errors;
+    errors.sort_by_key(|error| {
+        let pos = error.position();
+        (pos.row, pos.col)
+    });
+    Report {effects, errors}
+}
+
+struct Checker<'a> {
+    functions: &'a HashMap<String, Vec<Token>>,
+    local: &'a HashSet<String>,
+    effects: HashMap<String, Option<Effect>>,
+    /// The functions whose effects are being worked out, so recursion can be spotted
+    in_progress: HashSet<String>,
+    errors: Vec<CheckError>,
+}
+
+impl Checker<'_> {
+    fn report(&mut self, report: bool, error: CheckError) {
+        if report {
+            self.errors.push(error);
+        }
+    }
+
+    /// Works out a named function's stack effect, once
+    fn effect_of(&mut self, name: &str) -> Option<Effect> {
+        if let Some(effect) = self.effects.get(name) {
+            return effect.clone();
+        }
+        //A function that calls itself can't have its effect worked out from its body
+        if !self.in_progress.insert(name.to_string()) {
+            return None;
+        }
+        let functions = self.functions;
+        let body = functions.get(name)?;
+        let report = self.local.contains(name) && !name.starts_with("test_");
+        if let Some(Token {kind: TokenType::Signature(signature), pos}) = body.first() {
+            //The declared effect is trusted, so recursive calls can use it too
+            let effect = Effect::from(signature);
+            self.effects.insert(name.to_string(), Some(effect.clone()));
+            self.in_progress.remove(name);
+            self.check_declared(body, signature, &effect, *pos, report);
+            return Some(effect);
+        }
+        let mut state = State::new(true);
+        self.run(body, &mut state, report);
+        let effect = state.effect();
+        self.in_progress.remove(name);
+        self.effects.insert(name.to_string(), effect.clone());
+        effect
+    }
+
+    /// Checks that a function's body does what its declared stack effect says
+    ///
+    /// The body starts with only its declared inputs, so taking more than that is an underflow.
+    fn check_declared(&mut self, body: &[Token], signature: &Signature, effect: &Effect, pos: TokenPosition, report: bool) {
+        let mut state = State::new(false);
+        state.inputs = effect.inputs.clone();
+        for i in (0..effect.inputs.len()).rev() {
+            state.push(Ty::Input(i), pos);
+        }
+        self.run(body, &mut state, report);
+        if state.lost {
+            return;
+        }
+        let fit = state.stack.len() == effect.outputs.len() && state.stack.iter().zip(&effect.outputs).all(|(val, wanted)| {
+            let wanted = match wanted {
+                Ty::Input(i) => &effect.inputs[*i],
+                wanted => wanted,
+            };
+            fits(&state.resolve(&val.ty), wanted) != Fit::No
+        });
+        if !fit {
+            let found: Vec<String> = state.stack.iter().map(|val| state.resolve(&val.ty).name().to_string()).collect();
+            let found = if found.is_empty() {"nothing".to_string()} else {found.join(" and ")};
+            self.report(report, CheckError::EffectMismatch(pos, signature.name.clone(), Signature::describe(&signature.outputs), found));
+        }
+    }
+
+    /// Fails if code that started on an empty stack doesn't have enough values
+    fn require(&mut self, state: &mut State, count: usize, pos: TokenPosition, culprit: &str, report: bool) {
+        if !state.open && !state.lost && state.stack.len() < count {
+            self.report(report, CheckError::StackUnderflow(pos, culprit.to_string(), count, state.stack.len()));
+            state.lose();
+        }
+    }
+
+    /// Pops a value that needs to be a certain type
+    fn take(&mut self, state: &mut State, pos: TokenPosition, culprit: &str, wanted: Ty, expected: &'static str, report: bool) -> Val {
+        let val = state.pop(pos);
+        match fits(&state.resolve(&val.ty), &wanted) {
+            Fit::No => self.report(report, CheckError::InvalidValues(pos, culprit.to_string(), Mismatch {
+                expected,
+                found: vec![(state.resolve(&val.ty).name().to_string(), val.pos)],
+            })),
+            _ => state.narrow(&val.ty, &wanted),
+        }
+        val
+    }
+
+    /// The effect of running a value, if it can be worked out
+    fn effect_of_value(&mut self, ty: &Ty) -> Option<Effect> {
+        match ty {
+            Ty::Block(effect) => effect.clone(),
+            Ty::Function(name) => self.effect_of(name),
+            _ => None,
+        }
+    }
+
+    /// Runs a block or function's effect on the stack
+    fn apply(&mut self, state: &mut State, effect: Option<Effect>, pos: TokenPosition, culprit: &str, report: bool) {
+        let Some(effect) = effect else {
+            state.lose();
+            return;
+        };
+        self.require(state, effect.inputs.len(), pos, culprit, report);
+        let mut args = Vec::new();
+        for (i, wanted) in effect.inputs.iter().enumerate() {
+            let arg = state.pop(pos);
+            let ty = state.resolve(&arg.ty);
+            match fits(&ty, wanted) {
+                Fit::No => self.report(report, CheckError::InvalidInput(pos, culprit.to_string(), i, wanted.name().to_string(), (ty.name().to_string(), arg.pos))),
+                _ => state.narrow(&arg.ty, wanted),
+            }
+            args.push(arg);
+        }
+        for output in effect.outputs {
+            match output {
+                Ty::Input(i) => state.stack.push(args[i].clone()),
+                ty => state.push(ty, pos),
+            }
+        }
+    }
+
+    /// Follows the tokens, updating the state and reporting problems
+    fn run(&mut self, tokens: &[Token], state: &mut State, report: bool) {
+        for token in tokens {
+            let pos = token.pos;
+            match &token.kind {
+                TokenType::Literal(value) => {
+                    let ty = match value {
+                        Value::Integer(_) => Ty::Int,
+                        Value::Float(_) => Ty::Float,
+                        Value::Boolean(_) => Ty::Bool,
+                        Value::String(_) => Ty::Str,
+                        Value::Block(body) => {
+                            let mut block = State::new(true);
+                            self.run(body, &mut block, report);
+                            Ty::Block(block.effect())
+                        },
+                        //Tags are functions without a body
+                        Value::Function(name) => match self.functions.get(name) {
+                            Some(body) if body.is_empty() => Ty::Tag(name.clone()),
+                            _ => Ty::Function(name.clone()),
+                        },
+                        Value::Tag(name) => Ty::Tag(name.clone()),
+                    };
+                    state.push(ty, pos);
+                },
+                TokenType::Op(op) => self.operator(op, state, pos, report),
+                //Declared effects are checked when the function's effect is worked out
+                TokenType::Signature(_) => (),
+                TokenType::Keyword(Keyword::EXIT) => return,
+                TokenType::Keyword(keyword) => self.keyword(keyword, state, pos, report),
+            }
+        }
+    }
+
+    fn operator(&mut self, op: &Operation, state: &mut State, pos: TokenPosition, report: bool) {
+        let culprit = format!("`{}`", op);
+        match op {
+            Operation::Not => {
+                self.require(state, 1, pos, &culprit, report);
+                self.take(state, pos, &culprit, Ty::Bool, "a bool", report);
+                state.push(Ty::Bool, pos);
+            },
+            Operation::And | Operation::Or => {
+                self.require(state, 2, pos, &culprit, report);
+                self.take(state, pos, &culprit, Ty::Bool, "two bools", report);
+                self.take(state, pos, &culprit, Ty::Bool, "two bools", report);
+                state.push(Ty::Bool, pos);
+            },
+            Operation::Equal | Operation::NotEqual | Operation::Lesser | Operation::LesserEqual | Operation::Greater | Operation::GreaterEqual => {
+                self.require(state, 2, pos, &culprit, report);
+                state.pop(pos);
+                state.pop(pos);
+                state.push(Ty::Bool, pos);
+            },
+            Operation::Run => {
+                self.require(state, 1, pos, &culprit, report);
+                let block = self.take(state, pos, &culprit, Ty::Block(None), "a block or function", report);
+                let ty = state.resolve(&block.ty);
+                let effect = self.effect_of_value(&ty);
+                //Name the function being run, rather than `$`
+                let culprit = match &ty {
+                    Ty::Function(name) => format!("`{}`", name),
+                    _ => "The block".to_string(),
+                };
+                self.apply(state, effect, pos, &culprit, report);
+            },
+            Operation::Add | Operation::Subtract | Operation::Multiply | Operation::Divide => {
+                self.require(state, 2, pos, &culprit, report);
+                let top = state.pop(pos);
+                let below = state.pop(pos);
+                let ty = self.arithmetic(op, state, &below, &top, pos, report);
+                state.push(ty, pos);
+            },
+        }
+    }
+
+    /// Works out the type of an arithmetic operator's result, reporting values that can't be used
+    fn arithmetic(&mut self, op: &Operation, state: &mut State, below: &Val, top: &Val, pos: TokenPosition, report: bool) -> Ty {
+        //Each pair of types the operator takes, value below first
+        let (rules, expected): (&[(Ty, Ty)], &'static str) = match op {
+            Operation::Add => (&[(Ty::Number, Ty::Number), (Ty::Str, Ty::Str)], "two numbers or two strings"),
+            Operation::Subtract => (&[(Ty::Number, Ty::Number)], "two numbers"),
+            Operation::Multiply => (&[(Ty::Number, Ty::Number), (Ty::Int, Ty::Str)], "two numbers, or an int and a string"),
+            _ => (&[(Ty::Number, Ty::Number), (Ty::Str, Ty::Int)], "two numbers, or a string and an index in it"),
+        };
+        let below_ty = state.resolve(&below.ty);
+        let top_ty = state.resolve(&top.ty);
+
+        let mut possible = Vec::new();
+        for rule in rules {
+            match (fits(&below_ty, &rule.0), fits(&top_ty, &rule.1)) {
+                (Fit::No, _) | (_, Fit::No) => (),
+                (Fit::Yes, Fit::Yes) => {
+                    possible = vec![rule];
+                    break;
+                },
+                _ => possible.push(rule),
+            }
+        }
+        let [rule] = possible.as_slice() else {
+            if possible.is_empty() {
+                self.report(report, CheckError::InvalidValues(pos, format!("`{}`", op), Mismatch {
+                    expected,
+                    found: vec![(below_ty.name().to_string(), below.pos), (top_ty.name().to_string(), top.pos)],
+                }));
+            }
+            return Ty::Unknown;
+        };
+        state.narrow(&below.ty, &rule.0);
+        state.narrow(&top.ty, &rule.1);
+        if rule.0 != Ty::Number {
+            return Ty::Str;
+        }
+        match (state.resolve(&below.ty), state.resolve(&top.ty)) {
+            _ if matches!(op, Operation::Divide) => Ty::Float,
+            (Ty::Int, Ty::Int) => Ty::Int,
+            (Ty::Float, _) | (_, Ty::Float) => Ty::Float,
+            _ => Ty::Number,
+        }
+    }
+
+    fn keyword(&mut self, keyword: &Keyword, state: &mut State, pos: TokenPosition, report: bool) {
+        let culprit = format!("`{}`", keyword);
+        match keyword {
+            //An empty stack is fine for these
+            Keyword::PRINT | Keyword::DROP => {
+                state.pop(pos);
+            },
+            Keyword::LOOP => {
+                self.require(state, 2, pos, &culprit, report);
+                let block = self.take(state, pos, &culprit, Ty::Block(None), "a block on top", report);
+                self.take(state, pos, &culprit, Ty::Bool, "a bool below the block", report);
+                let Some(effect) = self.effect_of_value(&state.resolve(&block.ty)) else {
+                    state.lose();
+                    return;
+                };
+                let condition = match effect.outputs.last() {
+                    Some(Ty::Input(i)) => effect.inputs[*i].clone(),
+                    Some(ty) => ty.clone(),
+                    None => Ty::Unknown,
+                };
+                if fits(&condition, &Ty::Bool) == Fit::No {
+                    self.report(report, CheckError::LoopConditionNotBool(pos, condition.name().to_string()));
+                    state.lose();
+                    return;
+                }
+                if effect.outputs.len() != effect.inputs.len() + 1 {
+                    self.report(report, CheckError::LoopUnbalanced(pos, effect.inputs.len(), effect.outputs.len()));
+                    state.lose();
+                    return;
+                }
+                //The body might not run at all
+                let mut cycled = state.clone();
+                self.apply(&mut cycled, Some(effect), pos, "The `loop` body", report);
+                cycled.pop(pos);
+                *state = merge(state.clone(), cycled, pos);
+            },
+            Keyword::GATE => {
+                self.require(state, 2, pos, &culprit, report);
+                let true_block = self.take(state, pos, &culprit, Ty::Block(None), "a block on top", report);
+                let below = state.pop(pos);
+                let false_block = match state.resolve(&below.ty) {
+                    Ty::Block(_) | Ty::Function(_) => {
+                        self.require(state, 1, pos, &culprit, report);
+                        self.take(state, pos, &culprit, Ty::Bool, "a bool below the two blocks", report);
+                        Some(below)
+                    },
+                    Ty::Bool => None,
+                    //It could be either, so the checker can't tell how many values the gate takes
+                    Ty::Unknown | Ty::Input(_) => {
+                        state.lose();
+                        return;
+                    },
+                    ty => {
+                        self.report(report, CheckError::InvalidValues(pos, culprit, Mismatch {
+                            expected: "a bool, or another block, below the block",
+                            found: vec![(ty.name().to_string(), below.pos)],
+                        }));
+                        state.lose();
+                        return;
+                    },
+                };
+                let mut taken = state.clone();
+                let effect = self.effect_of_value(&state.resolve(&true_block.ty));
+                self.apply(&mut taken, effect, pos, "The `gate` branch", report);
+                let mut skipped = state.clone();
+                if let Some(false_block) = false_block {
+                    let effect = self.effect_of_value(&state.resolve(&false_block.ty));
+                    self.apply(&mut skipped, effect, pos, "The `gate` branch", report);
+                }
+                *state = merge(taken, skipped, pos);
+            },
+            Keyword::DUPLICATE => {
+                self.require(state, 1, pos, &culprit, report);
+                let val = state.pop(pos);
+                state.stack.push(val.clone());
+                state.stack.push(val);
+            },
+            Keyword::SWAP => {
+                //Swap leaves the values where they were
+                self.require(state, 2, pos, &culprit, report);
+                let a = state.pop(pos);
+                let b = state.pop(pos);
+                state.stack.push(b);
+                state.stack.push(a);
+            },
+            Keyword::DEPTH => state.push(Ty::Int, pos),
+            Keyword::ROT | Keyword::NROT => {
+                self.require(state, 3, pos, &culprit, report);
+                let c = state.pop(pos);
+                let b = state.pop(pos);
+                let a = state.pop(pos);
+                let order = if let Keyword::ROT = keyword {[b, c, a]} else {[c, a, b]};
+                state.stack.extend(order);
+            },
+            Keyword::OVER => {
+                self.require(state, 2, pos, &culprit, report);
+                let b = state.pop(pos);
+                let a = state.pop(pos);
+                state.stack.extend([a.clone(), b, a]);
+            },
+            Keyword::TUCK => {
+                self.require(state, 2, pos, &culprit, report);
+                let b = state.pop(pos);
+                let a = state.pop(pos);
+                state.stack.extend([b.clone(), a, b]);
+            },
+            //The index counts from the bottom of the stack, so which value is moved isn't known
+            Keyword::PICK => {
+                self.require(state, 1, pos, &culprit, report);
+                self.take(state, pos, &culprit, Ty::Int, "an int index", report);
+                state.push(Ty::Unknown, pos);
+            },
+            Keyword::ROLL => {
+                self.require(state, 1, pos, &culprit, report);
+                self.take(state, pos, &culprit, Ty::Int, "an int index", report);
+                for val in &mut state.stack {
+                    val.ty = Ty::Unknown;
+                }
+            },
+            //Clearing takes every value from the caller too, which an effect can't show
+            Keyword::CLEAR => state.lose(),
+            Keyword::TYPE => {
+                self.require(state, 1, pos, &culprit, report);
+                state.pop(pos);
+                state.push(Ty::Unknown, pos);
+            },
+            Keyword::INPUT => state.push(Ty::Str, pos),
+            Keyword::STRLEN => {
+                self.require(state, 1, pos, &culprit, report);
+                self.take(state, pos, &culprit, Ty::Str, "a string", report);
+                state.push(Ty::Int, pos);
+            },
+            Keyword::ASSERT => {
+                self.require(state, 1, pos, &culprit, report);
+                let top = state.pop(pos);
+                match state.resolve(&top.ty) {
+                    Ty::Bool => (),
+                    Ty::Str => {
+                        self.require(state, 1, pos, &culprit, report);
+                        self.take(state, pos, &culprit, Ty::Bool, "a bool below the message", report);
+                    },
+                    //The message is optional, so how many values are taken isn't known
+                    Ty::Unknown | Ty::Input(_) => state.lose(),
+                    ty => self.report(report, CheckError::InvalidValues(pos, culprit, Mismatch {
+                        expected: "a bool, or a bool and a message",
+                        found: vec![(ty.name().to_string(), top.pos)],
+                    })),
+                }
+            },
+            Keyword::READ => {
+                self.require(state, 1, pos, &culprit, report);
+                self.take(state, pos, &culprit, Ty::Str, "a file path", report);
+                state.push(Ty::Str, pos);
+            },
+            Keyword::EXISTS => {
+                self.require(state, 1, pos, &culprit, report);
+                self.take(state, pos, &culprit, Ty::Str, "a file path", report);
+                state.push(Ty::Bool, pos);
+            },
+            Keyword::DELETE => {
+                self.require(state, 1, pos, &culprit, report);
+                self.take(state, pos, &culprit, Ty::Str, "a file path", report);
+            },
+            Keyword::WRITE | Keyword::APPEND => {
+                self.require(state, 2, pos, &culprit, report);
+                self.take(state, pos, &culprit, Ty::Str, "a file path", report);
+                self.take(state, pos, &culprit, Ty::Str, "a string below the file path", report);
+            },
+            //These push a different number of values each time
+            Keyword::READLINES => {
+                self.require(state, 1, pos, &culprit, report);
+                self.take(state, pos, &culprit, Ty::Str, "a file path", report);
+                state.lose();
+            },
+            Keyword::ARGS => state.lose(),
+            Keyword::ENV => {
+                self.require(state, 1, pos, &culprit, report);
+                self.take(state, pos, &culprit, Ty::Str, "a variable name", report);
+                //A string, or false if it isn't set
+                state.push(Ty::Unknown, pos);
+            },
+            Keyword::TRY => {
+                self.require(state, 2, pos, &culprit, report);
+                self.take(state, pos, &culprit, Ty::Block(None), "a handler block on top", report);
+                let body = self.take(state, pos, &culprit, Ty::Block(None), "a block below the handler", report);
+                //Only the path without errors is followed
+                let effect = self.effect_of_value(&state.resolve(&body.ty));
+                self.apply(state, effect, pos, "The `try` body", report);
+            },
+            Keyword::EXIT | Keyword::USE | Keyword::TRUE | Keyword::FALSE | Keyword::BREAK => (),
+        }
+    }
+}
+
+/// Works out the stack effect of every function in a table
+pub fn effects_of(functions: &HashMap<String, Vec<Token>>) -> HashMap<String, Option<Effect>> {
+    let names: HashSet<String> = functions.keys().cloned().collect();
+    check(&[], functions, &names).effects.into_iter().collect()
+}
+
+/// A function's declared stack effect as it was written, or the one the checker worked out
+pub fn describe_effect(body: &[Token], effect: Option<&Option<Effect>>) -> Option<String> {
+    match (body.first(), effect) {
+        (Some(Token {kind: TokenType::Signature(signature), ..}), _) => Some(signature.to_string()),
+        (_, Some(Some(effect))) => Some(effect.to_string()),
+        _ => None,
+    }
+}
@@ -0,0 +1,180 @@
+use std::{collections::{BTreeMap, HashMap}, fmt::Display};
+
+use crate::{lexer::{LibrarySearch, Token, TokenPosition, TokenType, find_definition, tokenize}, types::{Keyword, Value}, vm::{Context, Stack, Tracer}};
+
+/// A tracer that records which tokens ran and which way each `gate` went, for coverage reports
+///
+/// Tokens are recorded with the function they ran in, so tokens from libraries, which have
+/// positions in the library's own source, aren't counted as lines of the file. Blocks count as
+/// part of the function they are run from.
+pub struct Coverage {
+    /// How many times each token ran, by the function it ran in (`None` for the top level), row and column
+    hits: HashMap<(Option<String>, usize, usize), u64>,
+    /// How many times each `gate` ran its block on top, and how many times it didn't, keyed like `hits`
+    branches: HashMap<(Option<String>, usize, usize), [u64; 2]>,
+    /// How many times each named function was called
+    calls: HashMap<String, u64>,
+    /// The name of each function and block that is running, `None` for blocks
+    frames: Vec<Option<String>>,
+}
+
+impl Coverage {
+    pub fn new() -> Self {
+        Coverage {hits: HashMap::new(), branches: HashMap::new(), calls: HashMap::new(), frames: Vec::new()}
+    }
+
+    /// The function that the code running right now counts towards, or `None` for the top level
+    fn current_function(&self) -> Option<String> {
+        self.frames.iter().rev().flatten().next().cloned()
+    }
+
+    /// Writes what was recorded as an lcov tracefile, for one source file
+    ///
+    /// Every line with code on it is listed, so the lines that never ran show up too. Each `gate`
+    /// has two branches, the block on top and the one below it, or skipping if there is only one.
+    ///
+    /// **Arguments:**
+    /// * `file`: The path of the source file, as it should appear in the report
+    /// * `source`: The source code that ran
+    /// * `context`: What the source can load and where from, as it needs to be tokenized again
+    ///
+    /// **Returns:** The report, or `None` if the source can't be tokenized
+    pub fn lcov(&self, file: &str, source: &str, context: &Context) -> Option<String> {
+        let mut functions = HashMap::new();
+        let top_level = tokenize(source, &mut functions, &context.permissions, &LibrarySearch::new(Some(file), &context.lib_paths)).ok()?;
+
+        //Functions from libraries have no lines in this file
+        let mut local: Vec<(String, TokenPosition)> = functions.keys()
+            .filter_map(|name| Some((name.clone(), find_definition(source, name)?)))
+            .collect();
+        local.sort_by_key(|(name, pos)| (pos.row, pos.col, name.clone()));
+
+        let mut tokens = Vec::new();
+        collect_tokens(&top_level, &mut tokens);
+        for (name, _) in &local {
+            collect_tokens(&functions[name], &mut tokens);
+        }
+        tokens.sort_by_key(|token| (token.pos.row, token.pos.col));
+
+        //Only what ran at the top level or in this file's functions is about its lines
+        let in_file = |function: &Option<String>| function.as_ref().is_none_or(|name| local.iter().any(|(local, _)| local == name));
+        let mut hits: HashMap<(usize, usize), u64> = HashMap::new();
+        for ((function, row, col), count) in &self.hits {
+            if in_file(function) {
+                *hits.entry((*row, *col)).or_default() += count;
+            }
+        }
+        let mut branches: HashMap<(usize, usize), [u64; 2]> = HashMap::new();
+        for ((function, row, col), counts) in &self.branches {
+            if in_file(function) {
+                let total = branches.entry((*row, *col)).or_default();
+                total[0] += counts[0];
+                total[1] += counts[1];
+            }
+        }
+
+        let hits_of = |token: &Token| hits.get(&(token.pos.row, token.pos.col)).copied().unwrap_or(0);
+        //Tests are run without being called, so a function also counts as called when its first token ran
+        let calls: Vec<u64> = local.iter()
+            .map(|(name, _)| self.calls.get(name).copied().unwrap_or(0).max(functions[name].first().map_or(0, hits_of)))
+            .collect();
+
+        let mut lines: BTreeMap<usize, u64> = BTreeMap::new();
+        //Each gate's position, and how many times it took each way if it ever ran
+        let mut gates: Vec<(TokenPosition, Option<[u64; 2]>)> = Vec::new();
+        for token in &tokens {
+            let pos = token.pos;
+            let hits = hits_of(token);
+            //A line counts as run as many times as its most run token
+            let line = lines.entry(pos.row).or_default();
+            *line = (*line).max(hits);
+            if let TokenType::Keyword(Keyword::GATE) = token.kind {
+                //A gate that failed before deciding ran without taking either way
+                let taken = branches.get(&(pos.row, pos.col)).copied().unwrap_or_default();
+                gates.push((pos, (hits > 0).then_some(taken)));
+            }
+        }
+
+        let mut report = format!("TN:\nSF:{}\n", file);
+        for (name, pos) in &local {
+            report.push_str(&format!("FN:{},{}\n", pos.row, name));
+        }
+        for ((name, _), calls) in local.iter().zip(&calls) {
+            report.push_str(&format!("FNDA:{},{}\n", calls, name));
+        }
+        let functions_hit = calls.iter().filter(|calls| **calls > 0).count();
+        report.push_str(&format!("FNF:{}\nFNH:{}\n", local.len(), functions_hit));
+
+        let mut branches_hit = 0;
+        let mut block = 0;
+        let mut last_row = 0;
+        for (pos, taken) in &gates {
+            //Blocks are numbered within each line
+            block = if pos.row == last_row {block + 1} else {0};
+            last_row = pos.row;
+            //Branches of a gate that never ran are written as `-`
+            let counts = match taken {
+                Some(counts) => counts.map(|count| count.to_string()),
+                None => ["-".to_string(), "-".to_string()],
+            };
+            branches_hit += taken.map_or(0, |counts| counts.iter().filter(|count| **count > 0).count());
+            for (branch, count) in counts.iter().enumerate() {
+                report.push_str(&format!("BRDA:{},{},{},{}\n", pos.row, block, branch, count));
+            }
+        }
+        report.push_str(&format!("BRF:{}\nBRH:{}\n", gates.len() * 2, branches_hit));
+
+        for (row, hits) in &lines {
+            report.push_str(&format!("DA:{},{}\n", row, hits));
+        }
+        let lines_hit = lines.values().filter(|hits| **hits > 0).count();
+        report.push_str(&format!("LF:{}\nLH:{}\nend_of_record\n", lines.len(), lines_hit));
+        Some(report)
+    }
+}
+
+/// Gathers tokens, along with the ones inside their blocks
+fn collect_tokens<'a>(tokens: &'a [Token], into: &mut Vec<&'a Token>) {
+    for token in tokens {
+        into.push(token);
+        if let TokenType::Literal(Value::Block(block)) = &token.kind {
+            collect_tokens(block, into);
+        }
+    }
+}
+
+impl Default for Coverage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tracer for Coverage {
+    fn before(&mut self, _token: &dyn Display, pos: TokenPosition, _stack: &Stack) -> bool {
+        *self.hits.entry((self.current_function(), pos.row, pos.col)).or_default() += 1;
+        true
+    }
+
+    fn enter(&mut self, name: Option<&str>, _called_at: TokenPosition, tail: bool) {
+        if let Some(name) = name {
+            *self.calls.entry(name.to_string()).or_default() += 1;
+        }
+        let name = name.map(str::to_string);
+        match self.frames.last_mut() {
+            //A block that ends a function is still part of it
+            Some(last) if tail => if name.is_some() {
+                *last = name;
+            },
+            _ => self.frames.push(name),
+        }
+    }
+
+    fn leave(&mut self) {
+        self.frames.pop();
+    }
+
+    fn branch(&mut self, pos: TokenPosition, taken: bool) {
+        let counts = self.branches.entry((self.current_function(), pos.row, pos.col)).or_default();
+        counts[if taken {0} else {1}] += 1;
+    }
+}
@@ -0,0 +1,239 @@
+use std::{collections::HashSet, fmt::Display, io::Write};
+
+use crate::{lexer::TokenPosition, types::Value, vm::{Stack, Tracer}};
+
+/// When the debugger should next pause
+#[derive(Debug, Clone, PartialEq)]
+enum Mode {
+    /// Before the next token, even inside a call
+    Step,
+    /// Before the next token that isn't inside a call made at this depth
+    Over(usize),
+    /// Before the next token after the frame at this depth returns
+    Out(usize),
+    /// Only at breakpoints and `break`
+    Continue,
+}
+
+/// A function or block that is running
+#[derive(Debug, Clone)]
+struct Frame {
+    /// The function's name, or `None` for a block
+    name: Option<String>,
+    called_at: TokenPosition,
+}
+
+/// An interactive debugger, which pauses the code to show the stack and take commands
+///
+/// Commands are read from stdin and everything it shows goes to stderr, so the program's
+/// own output stays apart.
+pub struct Debugger {
+    /// The source code being debugged, for showing where it is paused
+    lines: Vec<String>,
+    /// The functions breakpoints can be put on
+    functions: HashSet<String>,
+    mode: Mode,
+    line_breakpoints: HashSet<usize>,
+    function_breakpoints: HashSet<String>,
+    frames: Vec<Frame>,
+    /// Why the next token should be paused at, even when continuing
+    pending: Option<String>,
+    /// The row of the last token that was run, so a line breakpoint pauses once each time the line is reached
+    last_row: usize,
+    /// The command an empty line repeats
+    last_command: String,
+}
+
+impl Debugger {
+    /// Makes a debugger that pauses before the first token
+    ///
+    /// **Arguments:**
+    /// * `source`: The source code being debugged
+    /// * `functions`: The functions the code can call, including ones from libraries
+    pub fn new(source: &str, functions: HashSet<String>) -> Self {
+        Debugger {
+            lines: source.lines().map(str::to_string).collect(),
+            functions,
+            mode: Mode::Step,
+            line_breakpoints: HashSet::new(),
+            function_breakpoints: HashSet::new(),
+            frames: Vec::new(),
+            pending: None,
+            last_row: 0,
+            last_command: "step".to_string(),
+        }
+    }
+
+    /// Shows where the code is and takes commands until one resumes it
+    ///
+    /// **Returns:** `false` if the user stopped the program
+    fn pause(&mut self, reason: Option<String>, pos: TokenPosition, stack: &Stack) -> bool {
+        match reason {
+            Some(reason) => eprintln!("Paused at {}:{} ({})", pos.row, pos.col, reason),
+            None => eprintln!("Paused at {}:{}", pos.row, pos.col),
+        }
+        self.show_line(pos);
+        self.show_stack(stack);
+        loop {
+            eprint!("(debug) ");
+            let _ = std::io::stderr().flush();
+            let mut line = String::new();
+            //Without any more commands the program just finishes
+            if std::io::stdin().read_line(&mut line).unwrap_or(0) == 0 {
+                self.mode = Mode::Continue;
+                self.line_breakpoints.clear();
+                self.function_breakpoints.clear();
+                eprintln!();
+                return true;
+            }
+            let line = match line.trim() {
+                "" => self.last_command.clone(),
+                line => line.to_string(),
+            };
+            self.last_command = line.clone();
+            let (command, argument) = match line.split_once(' ') {
+                Some((command, argument)) => (command, argument.trim()),
+                None => (line.as_str(), ""),
+            };
+            let depth = self.frames.len();
+            match command {
+                "s" | "step" => self.mode = Mode::Step,
+                "n" | "next" => self.mode = Mode::Over(depth),
+                "o" | "out" => self.mode = Mode::Out(depth),
+                "c" | "continue" => self.mode = Mode::Continue,
+                "b" | "break" | "d" | "delete" if argument.is_empty() => {
+                    eprintln!("Give a line number or function name");
+                    continue;
+                },
+                "b" | "break" => {
+                    let name = argument.trim_start_matches('@');
+                    match argument.parse::<usize>() {
+                        Ok(row) if row == 0 || row > self.lines.len() => eprintln!("No line {}, the file has {} lines", row, self.lines.len()),
+                        Ok(row) => {
+                            self.line_breakpoints.insert(row);
+                        },
+                        Err(_) if !self.functions.contains(name) => eprintln!("No function named '{}'", name),
+                        Err(_) => {
+                            self.function_breakpoints.insert(name.to_string());
+                        },
+                    }
+                    continue;
+                },
+                "d" | "delete" => {
+                    let removed = match argument.parse() {
+                        Ok(row) => self.line_breakpoints.remove(&row),
+                        Err(_) => self.function_breakpoints.remove(argument.trim_start_matches('@')),
+                    };
+                    if !removed {
+                        eprintln!("No breakpoint at '{}'", argument);
+                    }
+                    continue;
+                },
+                "stack" => {
+                    self.show_stack(stack);
+                    continue;
+                },
+                "w" | "where" => {
+                    self.show_frames(pos);
+                    continue;
+                },
+                "q" | "quit" => return false,
+                _ => {
+                    if command != "h" && command != "help" {
+                        eprintln!("Unknown command '{}'", command);
+                    }
+                    print_help();
+                    continue;
+                },
+            }
+            return true;
+        }
+    }
+
+    fn show_line(&self, pos: TokenPosition) {
+        let line = self.lines.get(pos.row - 1).map_or("", String::as_str);
+        let width = pos.row.to_string().len();
+        eprintln!("{} | {}", pos.row, line);
+        eprintln!("{:>width$} | {}^", "", " ".repeat(pos.col - 1));
+    }
+
+    /// Shows the stack from the bottom up, with the top last
+    fn show_stack(&self, stack: &Stack) {
+        let values: Vec<String> = stack.values().map(Value::describe).collect();
+        if values.is_empty() {
+            eprintln!("stack: (empty)");
+        } else {
+            eprintln!("stack: {}", values.join(" "));
+        }
+    }
+
+    /// Shows every running function and block, innermost first, with where each one is
+    fn show_frames(&self, pos: TokenPosition) {
+        let mut at = pos;
+        for frame in self.frames.iter().rev() {
+            let name = frame.name.as_ref().map_or("a block".to_string(), |name| format!("`{}`", name));
+            eprintln!("  in {} at {}:{}", name, at.row, at.col);
+            at = frame.called_at;
+        }
+        eprintln!("  in the top level at {}:{}", at.row, at.col);
+    }
+}
+
+impl Tracer for Debugger {
+    fn enter(&mut self, name: Option<&str>, called_at: TokenPosition, tail: bool) {
+        if let Some(name) = name && self.function_breakpoints.contains(name) {
+            self.pending = Some(format!("breakpoint on `{}`", name));
+        }
+        let name = name.map(str::to_string);
+        match self.frames.last_mut() {
+            //The frame it replaces returns to the same place, and a block is still part of the function it ends
+            Some(last) if tail => if name.is_some() {
+                last.name = name;
+            },
+            _ => self.frames.push(Frame {name, called_at}),
+        }
+    }
+
+    fn leave(&mut self) {
+        self.frames.pop();
+    }
+
+    /// `break` pauses before the next token
+    fn interrupt(&mut self, pos: TokenPosition) {
+        self.pending = Some(format!("after `break` at {}:{}", pos.row, pos.col));
+    }
+
+    fn before(&mut self, _token: &dyn Display, pos: TokenPosition, stack: &Stack) -> bool {
+        let depth = self.frames.len();
+        let row = pos.row;
+        //Steps need no reason to be shown
+        let reason = match (&self.pending, &self.mode) {
+            (Some(reason), _) => Some(Some(reason.clone())),
+            (None, Mode::Step) => Some(None),
+            (None, Mode::Over(at)) if depth <= *at => Some(None),
+            (None, Mode::Out(at)) if depth < *at => Some(None),
+            (None, _) if row != self.last_row && self.line_breakpoints.contains(&row) => Some(Some(format!("breakpoint on line {}", row))),
+            (None, _) => None,
+        };
+        self.last_row = row;
+        self.pending = None;
+        match reason {
+            Some(reason) => self.pause(reason, pos, stack),
+            None => true,
+        }
+    }
+}
+
+fn print_help() {
+    eprintln!("Commands:");
+    eprintln!("    s, step                Run the next token, going into calls");
+    eprintln!("    n, next                Run the next token, going over calls");
+    eprintln!("    o, out                 Run until the current function or block returns");
+    eprintln!("    c, continue            Run until a breakpoint or `break`");
+    eprintln!("    b, break <line|name>   Pause when a line is reached or a function is called");
+    eprintln!("    d, delete <line|name>  Remove a breakpoint");
+    eprintln!("    stack                  Show the stack");
+    eprintln!("    w, where               Show the running functions and blocks");
+    eprintln!("    q, quit                Stop the program");
+    eprintln!("An empty line repeats the last command.");
+}
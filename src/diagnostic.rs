@@ -0,0 +1,283 @@
+use std::io::IsTerminal;
+
+use crate::{checker::CheckError, lexer::{IdentifierHint, TokenPosition, TokenizerError}, linter::LintWarning, vm::{Context, RuntimeError}};
+
+/// How errors are shown
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorFormat {
+    /// Colored text, with the code the error is about underlined
+    Text,
+    /// One JSON object per line, for editors and other tools
+    Json,
+}
+
+/// When errors are shown in color
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColorChoice {
+    /// Only when stderr is a terminal and the `NO_COLOR` environment variable isn't set
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Whether colors should be used right now
+    pub fn enabled(self) -> bool {
+        match self {
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+            //See https://no-color.org, an empty NO_COLOR doesn't count
+            ColorChoice::Auto => std::env::var_os("NO_COLOR").is_none_or(|value| value.is_empty())
+                && std::io::stderr().is_terminal(),
+        }
+    }
+}
+
+/// An error ready to be shown to the user, with the code it is about
+#[derive(Debug)]
+pub struct Diagnostic {
+    /// What kind of error it is, eg. "Syntax error"
+    pub kind: &'static str,
+    /// The stable error code, eg. "E0102"
+    pub code: &'static str,
+    pub message: String,
+    pub pos: TokenPosition,
+    /// Other places in the code that help explain the error
+    pub notes: Vec<Note>,
+    /// The code and message of the error that caused this one, eg. why a library is invalid
+    pub cause: Option<(&'static str, String)>,
+}
+
+#[derive(Debug)]
+pub struct Note {
+    pub message: String,
+    pub pos: TokenPosition,
+}
+
+impl From<&TokenizerError> for Diagnostic {
+    fn from(error: &TokenizerError) -> Self {
+        let mut notes = Vec::new();
+        if let TokenizerError::UnknownIdentifier(_, name, Some(IdentifierHint::DefinedAfterExit(def))) = error {
+            notes.push(Note {message: format!("'{}' is defined here", name), pos: *def});
+        }
+        let cause = match error {
+            TokenizerError::InvalidLibrary(_, _, internal_error) => Some((internal_error.code(), internal_error.message())),
+            _ => None,
+        };
+        Diagnostic {
+            kind: error.kind(),
+            code: error.code(),
+            message: error.message(),
+            pos: error.position(),
+            notes,
+            cause,
+        }
+    }
+}
+
+impl From<&RuntimeError> for Diagnostic {
+    fn from(error: &RuntimeError) -> Self {
+        let pos = error.position();
+        //A value pushed by the erroring token itself is already underlined
+        let notes = error.culprits().iter()
+            .filter(|(_, origin)| *origin != pos)
+            .map(|(name, origin)| Note {message: format!("this {} was pushed here", name), pos: *origin})
+            .collect();
+        Diagnostic {
+            kind: error.kind(),
+            code: error.code(),
+            message: error.message(),
+            pos,
+            notes,
+            cause: None,
+        }
+    }
+}
+
+impl From<&CheckError> for Diagnostic {
+    fn from(error: &CheckError) -> Self {
+        let pos = error.position();
+        let notes = error.culprits().iter()
+            .filter(|(_, origin)| *origin != pos)
+            .map(|(name, origin)| Note {message: format!("this {} was pushed here", name), pos: *origin})
+            .collect();
+        Diagnostic {
+            kind: error.kind(),
+            code: error.code(),
+            message: error.message(),
+            pos,
+            notes,
+            cause: None,
+        }
+    }
+}
+
+impl From<&LintWarning> for Diagnostic {
+    fn from(warning: &LintWarning) -> Self {
+        Diagnostic {
+            kind: "Lint warning",
+            code: warning.lint.code(),
+            //The name is what's used to allow or disable the lint
+            message: format!("{} ({})", warning.message, warning.lint),
+            pos: warning.pos,
+            notes: warning.note.iter().map(|(message, pos)| Note {message: message.clone(), pos: *pos}).collect(),
+            cause: None,
+        }
+    }
+}
+
+impl Diagnostic {
+    /// Prints the diagnostic to stderr
+    ///
+    /// **Arguments:**
+    /// * `source`: The source code the error came from
+    /// * `file`: The path of the source file, if it came from one
+    /// * `context`: The settings for how to show the diagnostic
+    pub fn print(&self, source: &str, file: Option<&str>, context: &Context) {
+        match context.error_format {
+            ErrorFormat::Text => self.print_text(source, context.color.enabled()),
+            ErrorFormat::Json => eprintln!("{}", self.to_json(source, file)),
+        }
+    }
+
+    /// Prints the diagnostic, underlining the code it is about, followed by its notes
+    fn print_text(&self, source: &str, color: bool) {
+        let width = self.notes.iter()
+            .map(|note| note.pos.row)
+            .fold(self.pos.row, usize::max)
+            .to_string().len();
+
+        eprintln!("{}[{}]({}:{}): {}", self.kind, self.code, self.pos.row, self.pos.col, self.message);
+        print_snippet(source, self.pos, width, '^', color.then_some("\x1b[38;5;196m"));
+        for note in &self.notes {
+            eprintln!("note({}:{}): {}", note.pos.row, note.pos.col, note.message);
+            print_snippet(source, note.pos, width, '-', color.then_some("\x1b[38;5;39m"));
+        }
+    }
+
+    /// Turns the diagnostic into a single line JSON object
+    ///
+    /// Spans end just after the last character, so `end.col` is one past the underline.
+    fn to_json(&self, source: &str, file: Option<&str>) -> String {
+        let notes: Vec<String> = self.notes.iter().map(|note| format!(
+            "{{\"message\":{},\"row\":{},\"col\":{},\"span\":{}}}",
+            json_string(&note.message), note.pos.row, note.pos.col, json_span(source, note.pos),
+        )).collect();
+        let cause = match &self.cause {
+            Some((code, message)) => format!("{{\"code\":{},\"message\":{}}}", json_string(code), json_string(message)),
+            None => "null".to_string(),
+        };
+        format!(
+            "{{\"kind\":{},\"code\":{},\"message\":{},\"file\":{},\"row\":{},\"col\":{},\"span\":{},\"notes\":[{}],\"cause\":{}}}",
+            json_string(self.kind),
+            json_string(self.code),
+            json_string(&self.message),
+            file.map_or("null".to_string(), json_string),
+            self.pos.row,
+            self.pos.col,
+            json_span(source, self.pos),
+            notes.join(","),
+            cause,
+        )
+    }
+}
+
+/// Prints an error about a whole file, like one that can't be read, to stderr
+///
+/// In JSON it has the same fields as a diagnostic, with no position.
+///
+/// **Arguments:**
+/// * `code`: The stable error code, eg. "E0001"
+/// * `message`: What went wrong
+/// * `cause`: The code and message of the error that caused this one, eg. why a library is invalid
+/// * `file`: The path of the file
+/// * `context`: The settings for how to show the error
+pub fn print_file_error(code: &str, message: &str, cause: Option<(&str, String)>, file: &str, context: &Context) {
+    match (context.error_format, cause) {
+        (ErrorFormat::Text, Some((_, cause))) => eprintln!("{}: {}", message, cause),
+        (ErrorFormat::Text, None) => eprintln!("{}", message),
+        (ErrorFormat::Json, cause) => eprintln!(
+            "{{\"kind\":\"File error\",\"code\":{},\"message\":{},\"file\":{},\"row\":null,\"col\":null,\"span\":null,\"notes\":[],\"cause\":{}}}",
+            json_string(code),
+            json_string(message),
+            json_string(file),
+            cause.map_or("null".to_string(), |(code, message)| format!("{{\"code\":{},\"message\":{}}}", json_string(code), json_string(&message))),
+        ),
+    }
+}
+
+/// Turns the span of the token at `pos` into a JSON object
+fn json_span(source: &str, pos: TokenPosition) -> String {
+    let line = source.lines().nth(pos.row - 1).unwrap_or("");
+    format!(
+        "{{\"start\":{{\"row\":{},\"col\":{}}},\"end\":{{\"row\":{},\"col\":{}}}}}",
+        pos.row, pos.col, pos.row, pos.col + span_length(line, pos.col),
+    )
+}
+
+/// Quotes a string for JSON, escaping anything that needs it
+pub fn json_string(string: &str) -> String {
+    let mut quoted = String::with_capacity(string.len() + 2);
+    quoted.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Prints a line of source code with the token at `pos` underlined
+///
+/// **Arguments:**
+/// * `source`: The source code to take the line from
+/// * `pos`: Where the token starts
+/// * `width`: How wide the line numbers are, so every snippet lines up
+/// * `marker`: The character to underline with
+/// * `color`: The escape code for the underline's color, or `None` for no color
+fn print_snippet(source: &str, pos: TokenPosition, width: usize, marker: char, color: Option<&str>) {
+    let line = source.lines().nth(pos.row - 1).unwrap_or("");
+    let underline = marker.to_string().repeat(span_length(line, pos.col));
+    eprintln!("{:>width$} | {}", pos.row, line);
+    match color {
+        Some(color) => eprintln!("{:>width$} | {}{}{}\x1b[0m", "", " ".repeat(pos.col - 1), color, underline),
+        None => eprintln!("{:>width$} | {}{}", "", " ".repeat(pos.col - 1), underline),
+    }
+}
+
+/// How many characters the token starting at `col` takes up, stopping at the end of the line
+///
+/// Strings and blocks are underlined up to their closing quote or brace.
+pub fn span_length(line: &str, col: usize) -> usize {
+    let rest: Vec<char> = line.chars().skip(col - 1).collect();
+    let length = match rest.first() {
+        Some('"') => {
+            let mut escaped = false;
+            rest.iter().skip(1).position(|&c| {
+                let closes = c == '"' && !escaped;
+                escaped = c == '\\' && !escaped;
+                closes
+            }).map_or(rest.len(), |i| i + 2)
+        },
+        Some('{') => {
+            let mut depth = 0;
+            rest.iter().position(|&c| {
+                match c {
+                    '{' => depth += 1,
+                    '}' => depth -= 1,
+                    _ => (),
+                }
+                depth == 0
+            }).map_or(rest.len(), |i| i + 1)
+        },
+        _ => rest.iter().position(|c| c.is_whitespace()).unwrap_or(rest.len()),
+    };
+    length.max(1)
+}
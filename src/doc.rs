@@ -0,0 +1,101 @@
+use std::collections::HashMap;
+
+use crate::{checker::{describe_effect, effects_of}, lexer::{Token, is_hidden}};
+
+/// How `stackathon doc` writes reference pages
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DocFormat {
+    Markdown,
+    /// A single page that needs no other files
+    Html,
+}
+
+/// A function or tag as it is listed in a reference page
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub name: String,
+    /// The stack effect of a function, or `None` for a tag
+    pub effect: Option<String>,
+    pub doc: Option<String>,
+}
+
+/// Works out what to list for each function and tag, leaving out tests and functions a library only uses itself
+///
+/// **Arguments:**
+/// * `names`: The functions and tags to list, in the order they are listed
+/// * `functions`: The function table, which effects are worked out from
+/// * `docs`: The doc text of each function and tag that has one
+pub fn entries(names: &[String], functions: &HashMap<String, Vec<Token>>, docs: &HashMap<String, String>) -> Vec<Entry> {
+    let effects = effects_of(functions);
+    names.iter()
+        .filter(|name| !is_hidden(name))
+        .filter(|name| !(name.starts_with("test_") && functions.get(*name).is_some_and(|body| !body.is_empty())))
+        .filter_map(|name| {
+            let body = functions.get(name)?;
+            let effect = if body.is_empty() {
+                None
+            } else {
+                Some(describe_effect(body, effects.get(name)).unwrap_or_else(|| "( unknown )".to_string()))
+            };
+            Some(Entry {name: name.clone(), effect, doc: docs.get(name).cloned()})
+        })
+        .collect()
+}
+
+/// Writes a reference page listing each function and tag with its stack effect and doc text
+///
+/// **Arguments:**
+/// * `title`: What the page is about, eg. the library's name
+/// * `entries`: What to list, in order
+/// * `format`: Whether to write Markdown or HTML
+pub fn render(title: &str, entries: &[Entry], format: DocFormat) -> String {
+    match format {
+        DocFormat::Markdown => render_markdown(title, entries),
+        DocFormat::Html => render_html(title, entries),
+    }
+}
+
+fn render_markdown(title: &str, entries: &[Entry]) -> String {
+    let mut page = format!("# {}\n", title);
+    if entries.is_empty() {
+        page.push_str("\nNo functions or tags are defined.\n");
+    }
+    for entry in entries {
+        page.push_str(&format!("\n## `{}`\n", entry.name));
+        match &entry.effect {
+            Some(effect) => page.push_str(&format!("`{}`\n", effect)),
+            None => page.push_str("Tag\n"),
+        }
+        if let Some(doc) = &entry.doc {
+            page.push_str(&format!("\n{}\n", doc));
+        }
+    }
+    page
+}
+
+fn render_html(title: &str, entries: &[Entry]) -> String {
+    let title = escape_html(title);
+    let mut page = format!("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n</head>\n<body>\n<h1>{}</h1>\n", title, title);
+    if entries.is_empty() {
+        page.push_str("<p>No functions or tags are defined.</p>\n");
+    }
+    for entry in entries {
+        let name = escape_html(&entry.name);
+        page.push_str(&format!("<section id=\"{}\">\n<h2><code>{}</code></h2>\n", name, name));
+        match &entry.effect {
+            Some(effect) => page.push_str(&format!("<p><code>{}</code></p>\n", escape_html(effect))),
+            None => page.push_str("<p>Tag</p>\n"),
+        }
+        //Blank lines in a doc comment start a new paragraph
+        for paragraph in entry.doc.iter().flat_map(|doc| doc.split("\n\n")) {
+            page.push_str(&format!("<p>{}</p>\n", escape_html(paragraph)));
+        }
+        page.push_str("</section>\n");
+    }
+    page.push_str("</body>\n</html>\n");
+    page
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
@@ -0,0 +1,107 @@
+use crate::lexer::{RawKind, RawToken, TokenizerError, scan};
+
+/// How far each level of block is indented
+const INDENT: &str = "    ";
+
+/// What goes between two tokens
+enum Separator {
+    Space,
+    Newline,
+    BlankLine,
+}
+
+/// Formats stackathon source code
+///
+/// Tokens on the same line are separated by one space, and every line is indented by how deeply
+/// nested in blocks it is. Line breaks are kept, apart from runs of blank lines, which become one,
+/// and blank lines at the start or end of a block. The stack effect and `{` of a definition always
+/// go on the same line as its name. Comments and strings are never changed.
+///
+/// **Arguments:**
+/// * `code`: The source code to format
+///
+/// **Returns:** The formatted code, or the errors that stopped it from being formatted
+pub fn format(code: &str) -> Result<String, Vec<TokenizerError>> {
+    let tokens = scan(code)?;
+    let mut output = String::new();
+    let mut depth = 0;
+    //The row the previous token ended on
+    let mut last_row = 0;
+    let mut previous: Option<&RawToken> = None;
+    //The name of a definition whose body hasn't started yet, and whether it has a stack effect
+    let mut header: Option<(&RawToken, bool)> = None;
+    let mut in_signature = false;
+
+    for token in &tokens {
+        if token.kind == RawKind::CloseBrace {
+            depth -= 1;
+        }
+        let separator = match (previous, header) {
+            (None, _) => None,
+            (Some(_), Some((name, false))) if token.text == "(" || token.kind == RawKind::OpenBrace => {
+                if follows_name(code, name, token) {
+                    Some(Separator::Space)
+                } else {
+                    //Only a brace straight after the name starts the body, so a block after a tag stays apart
+                    Some(Separator::BlankLine)
+                }
+            },
+            (Some(_), Some((_, true))) if token.kind == RawKind::OpenBrace => Some(Separator::Space),
+            (Some(_), _) if token.pos.row == last_row => Some(Separator::Space),
+            (Some(previous), _) => {
+                let blank_line = token.pos.row > last_row + 1
+                    && previous.kind != RawKind::OpenBrace
+                    && token.kind != RawKind::CloseBrace;
+                Some(if blank_line {Separator::BlankLine} else {Separator::Newline})
+            },
+        };
+        match separator {
+            None => (),
+            Some(Separator::Space) => output.push(' '),
+            Some(separator) => {
+                output.push('\n');
+                if let Separator::BlankLine = separator {
+                    output.push('\n');
+                }
+                output.push_str(&INDENT.repeat(depth));
+            },
+        }
+        output.push_str(&token.text);
+
+        header = match (token.kind, header) {
+            (RawKind::Word, _) if token.text.starts_with('@') => Some((token, false)),
+            (RawKind::Word, Some((name, false))) if token.text == "(" => {
+                in_signature = true;
+                Some((name, true))
+            },
+            (RawKind::Word, Some(header)) if in_signature => {
+                in_signature = token.text != ")";
+                Some(header)
+            },
+            _ => None,
+        };
+        if token.kind == RawKind::OpenBrace {
+            depth += 1;
+        }
+        last_row = token.pos.row + token.text.matches('\n').count();
+        previous = Some(token);
+    }
+
+    //A comment with no closing `;` runs to the end of the code, including its last line break
+    if !output.is_empty() && !output.ends_with('\n') {
+        output.push('\n');
+    }
+    Ok(output)
+}
+
+/// Whether `token` comes straight after the whitespace character that ends a definition's name,
+/// which is the only place the lexer looks for its stack effect or body
+pub fn follows_name(code: &str, name: &RawToken, token: &RawToken) -> bool {
+    let end = name.pos.col + name.text.chars().count();
+    if token.pos.row == name.pos.row {
+        return token.pos.col == end + 1;
+    }
+    //The name has to end its line, so the newline is the whitespace after it
+    let line_length = code.split('\n').nth(name.pos.row - 1).map_or(0, |line| line.chars().count());
+    token.pos.row == name.pos.row + 1 && token.pos.col == 1 && line_length + 1 == end
+}
@@ -0,0 +1,230 @@
+use std::{fmt::Display, iter::Peekable, str::Chars};
+
+use crate::diagnostic::json_string;
+
+/// A JSON value, for talking to editors
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    /// Keys stay in the order they were written
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Makes an object from its keys and values
+    pub fn object<const N: usize>(pairs: [(&str, Json); N]) -> Json {
+        Json::Object(pairs.into_iter().map(|(key, value)| (key.to_string(), value)).collect())
+    }
+
+    /// Parses JSON text, `None` if it isn't valid
+    pub fn parse(text: &str) -> Option<Json> {
+        let mut chars = text.chars().peekable();
+        let value = parse_value(&mut chars)?;
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            None => Some(value),
+            Some(_) => None,
+        }
+    }
+
+    /// Looks up a key in an object
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(pairs) => pairs.iter().find(|(k, _)| k == key).map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        match self {
+            Json::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as usize),
+            _ => None,
+        }
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Self {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Self {
+        Json::String(s)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Self {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Self {
+        Json::Bool(b)
+    }
+}
+
+impl Display for Json {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            //Whole numbers, like ids and positions, are written without a decimal point
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => write!(f, "{}", json_string(s)),
+            Json::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            },
+            Json::Object(pairs) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in pairs.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}:{}", json_string(key), value)?;
+                }
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+fn parse_value(chars: &mut Peekable<Chars>) -> Option<Json> {
+    skip_whitespace(chars);
+    match chars.peek()? {
+        '{' => {
+            chars.next();
+            let mut pairs = Vec::new();
+            skip_whitespace(chars);
+            if chars.peek() == Some(&'}') {
+                chars.next();
+                return Some(Json::Object(pairs));
+            }
+            loop {
+                skip_whitespace(chars);
+                if chars.next()? != '"' {
+                    return None;
+                }
+                let key = parse_string(chars)?;
+                skip_whitespace(chars);
+                if chars.next()? != ':' {
+                    return None;
+                }
+                pairs.push((key, parse_value(chars)?));
+                skip_whitespace(chars);
+                match chars.next()? {
+                    ',' => continue,
+                    '}' => return Some(Json::Object(pairs)),
+                    _ => return None,
+                }
+            }
+        },
+        '[' => {
+            chars.next();
+            let mut values = Vec::new();
+            skip_whitespace(chars);
+            if chars.peek() == Some(&']') {
+                chars.next();
+                return Some(Json::Array(values));
+            }
+            loop {
+                values.push(parse_value(chars)?);
+                skip_whitespace(chars);
+                match chars.next()? {
+                    ',' => continue,
+                    ']' => return Some(Json::Array(values)),
+                    _ => return None,
+                }
+            }
+        },
+        '"' => {
+            chars.next();
+            parse_string(chars).map(Json::String)
+        },
+        't' | 'f' | 'n' => {
+            let mut word = String::new();
+            while chars.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+                word.push(chars.next()?);
+            }
+            match word.as_str() {
+                "true" => Some(Json::Bool(true)),
+                "false" => Some(Json::Bool(false)),
+                "null" => Some(Json::Null),
+                _ => None,
+            }
+        },
+        _ => {
+            let mut number = String::new();
+            while chars.peek().is_some_and(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')) {
+                number.push(chars.next()?);
+            }
+            number.parse().ok().map(Json::Number)
+        },
+    }
+}
+
+/// Parses the rest of a string, after its opening quote
+fn parse_string(chars: &mut Peekable<Chars>) -> Option<String> {
+    let mut string = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(string),
+            '\\' => match chars.next()? {
+                'n' => string.push('\n'),
+                't' => string.push('\t'),
+                'r' => string.push('\r'),
+                'b' => string.push('\u{8}'),
+                'f' => string.push('\u{c}'),
+                'u' => {
+                    let high = parse_hex(chars)?;
+                    //Characters outside the basic plane are written as two escapes
+                    let code = if (0xD800..0xDC00).contains(&high) {
+                        if chars.next()? != '\\' || chars.next()? != 'u' {
+                            return None;
+                        }
+                        let low = parse_hex(chars)?;
+                        0x10000 + ((high - 0xD800) << 10) + (low.checked_sub(0xDC00)? & 0x3FF)
+                    } else {
+                        high
+                    };
+                    string.push(char::from_u32(code)?);
+                },
+                c => string.push(c),
+            },
+            c => string.push(c),
+        }
+    }
+}
+
+fn parse_hex(chars: &mut Peekable<Chars>) -> Option<u32> {
+    let hex: String = (0..4).map(|_| chars.next()).collect::<Option<String>>()?;
+    u32::from_str_radix(&hex, 16).ok()
+}
@@ -1,10 +1,14 @@
-use std::{collections::HashMap, error::Error, fmt, iter::Peekable, str::Chars, sync::OnceLock};
+use std::{collections::HashMap, error::Error, fmt, iter::Peekable, path::{Path, PathBuf}, str::Chars, sync::OnceLock};
 
-use crate::{VERSION, serial::{ByteSized, SerializationError}, types::{Keyword, Operation, Value}};
+use crate::{DOCS_VERSION, VERSION, serial::{ByteSized, SerializationError}, types::{Capability, Keyword, Operation, Permissions, Signature, Value}};
 
 static KEYWORDS: OnceLock<HashMap<&'static str, Keyword>> = OnceLock::new(); 
 
+/// Joins a library's path to the name of a function it needs that wasn't imported, which code can't write
+const HIDDEN: char = '#';
+
 #[derive(Debug)]
+#[derive(PartialEq)]
 pub struct TokenPosition {
     pub row: usize,
     pub col: usize,
@@ -48,6 +52,19 @@ pub enum TokenType {
     Literal(Value),
     Op(Operation),
     Keyword(Keyword),
+    /// A declared stack effect, the first token of a function that has one
+    Signature(Signature),
+}
+
+impl fmt::Display for TokenType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenType::Literal(value) => write!(f, "{}", value.describe()),
+            TokenType::Op(op) => write!(f, "{}", op),
+            TokenType::Keyword(keyword) => write!(f, "{}", keyword),
+            TokenType::Signature(signature) => write!(f, "{}", signature),
+        }
+    }
 }
 
 impl ByteSized for TokenType {
@@ -67,6 +84,11 @@ impl ByteSized for TokenType {
                 let mut bytes = vec![0x03];
                 bytes.extend_from_slice(&k.to_bytes());
                 bytes
+            },
+            TokenType::Signature(signature) => {
+                let mut bytes = vec![0x04];
+                bytes.extend_from_slice(&signature.to_bytes());
+                bytes
             }
         }
     }
@@ -91,6 +113,10 @@ impl ByteSized for TokenType {
                 let (value, bytes_read) = Keyword::from_bytes(&bytes[1..])?;
                 Ok((TokenType::Keyword(value), bytes_read + 1))
             },
+            0x04 => {
+                let (value, bytes_read) = Signature::from_bytes(&bytes[1..])?;
+                Ok((TokenType::Signature(value), bytes_read + 1))
+            },
             _ => return Err(SerializationError::InvalidTagByte(bytes[0]))
         }
     }
@@ -136,16 +162,31 @@ impl ByteSized for Token {
 }
 
 
+/// Extra help for an unknown identifier
+#[derive(Debug)]
+pub enum IdentifierHint {
+    /// A keyword or function with a similar name
+    DidYouMean(String),
+    /// The identifier is defined, but the tokenizer stopped reading before the definition
+    DefinedAfterExit(TokenPosition),
+}
+
 #[derive(Debug)]
 pub enum TokenizerError {
     InvalidNumberFormat(TokenPosition),
     UnexpectedSymbol(TokenPosition, char),
-    UnknownIdentifier(TokenPosition, String),
+    UnknownIdentifier(TokenPosition, String, Option<IdentifierHint>),
     BlockHadNoEnd(TokenPosition),
     StringHadNoEnd(TokenPosition),
     FunctionHasMultipleDefinitions(TokenPosition, String),
-    UnknownLibrary(TokenPosition, String),
+    /// With every path that was tried
+    UnknownLibrary(TokenPosition, String, Vec<String>),
     InvalidLibrary(TokenPosition, String, SerializationError),
+    PermissionDenied(TokenPosition, Capability),
+    InvalidSignature(TokenPosition, String),
+    InvalidImport(TokenPosition, String),
+    /// A library defines a name that is already defined differently, with the library's path
+    ImportConflict(TokenPosition, String, String),
 }
 
 impl TokenizerError {
@@ -153,482 +194,1039 @@ impl TokenizerError {
         match self {
             TokenizerError::InvalidNumberFormat(pos) => *pos,
             TokenizerError::UnexpectedSymbol(pos, _) => *pos,
-            TokenizerError::UnknownIdentifier(pos, _) => *pos,
+            TokenizerError::UnknownIdentifier(pos, _, _) => *pos,
             TokenizerError::BlockHadNoEnd(pos) => *pos,
             TokenizerError::StringHadNoEnd(pos) => *pos,
             TokenizerError::FunctionHasMultipleDefinitions(pos, _) => *pos,
-            TokenizerError::UnknownLibrary(pos, _) => *pos,
+            TokenizerError::UnknownLibrary(pos, _, _) => *pos,
             TokenizerError::InvalidLibrary(pos, _, _) => *pos,
+            TokenizerError::PermissionDenied(pos, _) => *pos,
+            TokenizerError::InvalidSignature(pos, _) => *pos,
+            TokenizerError::InvalidImport(pos, _) => *pos,
+            TokenizerError::ImportConflict(pos, _, _) => *pos,
+        }
+    }
+
+    /// What kind of error this is, shown before its code
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TokenizerError::UnknownLibrary(_, _, _) | TokenizerError::InvalidLibrary(_, _, _) | TokenizerError::ImportConflict(_, _, _) => "Library error",
+            TokenizerError::PermissionDenied(_, _) => "Permission error",
+            _ => "Syntax error",
+        }
+    }
+
+    /// The stable code for this error, listed in the README
+    pub fn code(&self) -> &'static str {
+        match self {
+            TokenizerError::InvalidNumberFormat(_) => "E0101",
+            TokenizerError::UnexpectedSymbol(_, _) => "E0102",
+            TokenizerError::UnknownIdentifier(_, _, _) => "E0103",
+            TokenizerError::BlockHadNoEnd(_) => "E0104",
+            TokenizerError::StringHadNoEnd(_) => "E0105",
+            TokenizerError::FunctionHasMultipleDefinitions(_, _) => "E0106",
+            TokenizerError::UnknownLibrary(_, _, _) => "E0107",
+            TokenizerError::InvalidLibrary(_, _, _) => "E0108",
+            TokenizerError::PermissionDenied(_, _) => "E0109",
+            TokenizerError::InvalidSignature(_, _) => "E0110",
+            TokenizerError::InvalidImport(_, _) => "E0111",
+            TokenizerError::ImportConflict(_, _, _) => "E0112",
+        }
+    }
+
+    /// The error's description, without its kind or position
+    pub fn message(&self) -> String {
+        match self {
+            Self::InvalidNumberFormat(_) =>
+                "Invalid number format".to_string(),
+            Self::UnexpectedSymbol(_, c) =>
+                format!("Unexpected character '{}'", c),
+            Self::UnknownIdentifier(_, s, None) =>
+                format!("Unknown identifier '{}'", s),
+            Self::UnknownIdentifier(_, s, Some(IdentifierHint::DidYouMean(suggestion))) =>
+                format!("Unknown identifier '{}' — did you mean '{}'?", s, suggestion),
+            Self::UnknownIdentifier(_, s, Some(IdentifierHint::DefinedAfterExit(def))) =>
+                format!("Unknown identifier '{}' — it is defined at {}:{}, but that is after an 'exit', so it is never read", s, def.row, def.col),
+            Self::BlockHadNoEnd(_) =>
+                "Block has no matching brace".to_string(),
+            Self::StringHadNoEnd(_) =>
+                "String has no end.".to_string(),
+            Self::FunctionHasMultipleDefinitions(_, func) =>
+                format!("Function '{}' has multiple definitions.", func),
+            Self::UnknownLibrary(_, name, tried) if tried.is_empty() =>
+                format!("Library '{}' not found.", name),
+            Self::UnknownLibrary(_, name, tried) =>
+                format!("Library '{}' not found, looked for it at: {}", name, tried.join(", ")),
+            Self::InvalidLibrary(_, name, internal_error) =>
+                format!("Library '{}' is invalid: {}", name, internal_error),
+            Self::PermissionDenied(_, capability) =>
+                format!("The {} capability is not allowed.", capability),
+            Self::InvalidSignature(_, reason) =>
+                format!("Invalid stack effect: {}", reason),
+            Self::InvalidImport(_, reason) =>
+                format!("Invalid import: {}", reason),
+            Self::ImportConflict(_, library, name) =>
+                format!("Library '{}' defines '{}', which is already defined.", library, name),
         }
     }
 }
 
 impl fmt::Display for TokenizerError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Self::InvalidNumberFormat(pos) =>
-                write!(f, "Syntax error({}:{}): Invalid number format", pos.row, pos.col),
-            Self::UnexpectedSymbol(pos,c) =>
-                write!(f, "Syntax error({}:{}): Unexpected character '{}'", pos.row, pos.col, c),
-            Self::UnknownIdentifier(pos, s) =>
-                write!(f, "Syntax error({}:{}): Unknown identifier '{}'", pos.row, pos.col, s),
-            Self::BlockHadNoEnd(pos) => 
-                write!(f, "Syntax error({}:{}): Block has no matching brace", pos.row, pos.col),
-            Self::StringHadNoEnd(pos) =>
-                write!(f, "Syntax error({}:{}): String has no end.", pos.row, pos.col),
-            Self::FunctionHasMultipleDefinitions(pos, func) =>
-                write!(f, "Syntax error({}:{}): Function '{}' has multiple definitions.", pos.row, pos.col, func),
-            Self::UnknownLibrary(pos, name) =>
-                write!(f, "Library error({}:{}): Library '{}' not found.", pos.row, pos.col, name),
-            Self::InvalidLibrary(pos, name, internal_error) =>
-                write!(f, "Library error({}:{}): Library '{}' is invalid: {}", pos.row, pos.col, name, internal_error),
-        }
+        let pos = self.position();
+        write!(f, "{}[{}]({}:{}): {}", self.kind(), self.code(), pos.row, pos.col, self.message())
     }
 }
 
 impl Error for TokenizerError {}
 
 
-pub fn tokenize(code: &str, starting_position: Option<TokenPosition>, functions: &mut HashMap<String, Vec<Token>>) -> Result<Vec<Token>, TokenizerError> {
-    
-    let mut tokens = Vec::new();
-    
-    let mut code = code.chars().peekable();
+/// Turns stackathon source code into tokens
+/// 
+/// Named functions can be used before they are defined, so identifiers are only checked
+/// once every definition (including ones from libraries) has been collected.
+/// 
+/// The tokenizer carries on after an error, so every error in the code is found at once.
+/// 
+/// **Arguments:**
+/// * `code`: The source code to tokenize
+/// * `functions`: The function table, which gets every function definition added to it
+/// * `permissions`: What the code is allowed to use, `use` needs library loading
+/// * `libraries`: Where `use` looks for libraries
+/// 
+/// **Returns:** The tokens, or every error found in source order
+pub fn tokenize(code: &str, functions: &mut HashMap<String, Vec<Token>>, permissions: &Permissions, libraries: &LibrarySearch) -> Result<Vec<Token>, Vec<TokenizerError>> {
+    let mut cursor = Cursor::new(code);
+    let mut errors = Vec::new();
+    let tokens = tokenize_code(&mut cursor, None, functions, permissions, libraries, &mut errors);
+
+    let mut unresolved = Vec::new();
+    find_unresolved(&tokens, functions, &mut unresolved);
+    for definition in functions.values() {
+        find_unresolved(definition, functions, &mut unresolved);
+    }
+    for (pos, name) in unresolved {
+        let hint = match find_definition(code, &name) {
+            Some(def) => Some(IdentifierHint::DefinedAfterExit(def)),
+            None => suggest(&name, functions).map(IdentifierHint::DidYouMean),
+        };
+        errors.push(TokenizerError::UnknownIdentifier(pos, name, hint));
+    }
 
-    let mut position = match starting_position {None => TokenPosition {row: 1, col: 1}, Some(p) => p};
-    while let Some(character) = code.next() {
-        
+    if errors.is_empty() {
+        Ok(tokens)
+    } else {
+        errors.sort_by_key(|error| {
+            let pos = error.position();
+            (pos.row, pos.col)
+        });
+        Err(errors)
+    }
+}
 
-        //handles comments
-        if character == ';' {
-            while let Some(c) = code.next() {
-                position.col += 1;
-                if c == ';' {
-                    break;
+/// Collects every function reference in `tokens` (and any blocks in them) that isn't in the function table
+fn find_unresolved(tokens: &[Token], functions: &HashMap<String, Vec<Token>>, unresolved: &mut Vec<(TokenPosition, String)>) {
+    for token in tokens {
+        match &token.kind {
+            TokenType::Literal(Value::Function(name)) if !functions.contains_key(name) => unresolved.push((token.pos, name.clone())),
+            TokenType::Literal(Value::Block(block)) => find_unresolved(block, functions, unresolved),
+            _ => (),
+        }
+    }
+}
+
+/// Finds the closest keyword or function name to an unknown identifier
+/// 
+/// **Arguments:**
+/// * `name`: The unknown identifier
+/// * `functions`: The function table, including library functions
+fn suggest(name: &str, functions: &HashMap<String, Vec<Token>>) -> Option<String> {
+    //Short names only allow one typo, otherwise everything would be suggested
+    let max_distance = name.chars().count().div_ceil(3).max(1);
+    get_keywords().keys().copied()
+        .chain(functions.keys().map(|function| function.as_str()))
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .min()
+        .map(|(_, candidate)| candidate.to_string())
+}
+
+/// The Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a_char) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, b_char) in b.iter().enumerate() {
+            let substitution = previous[j] + if a_char == *b_char {0} else {1};
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+/// Looks through the raw code for a definition of `name`, skipping comments and strings
+/// 
+/// Used to find definitions that were never tokenized, because they come after an `exit`
+pub fn find_definition(code: &str, name: &str) -> Option<TokenPosition> {
+    let mut cursor = Cursor::new(code);
+    while let Some(character) = cursor.next() {
+        match character {
+            ';' | '"' => {
+                //Escaped quotes are skipped along with the character after the backslash
+                while let Some(c) = cursor.next() {
+                    if c == character {
+                        break;
+                    }
+                    if c == '\\' && character == '"' {
+                        cursor.next();
+                    }
                 }
-            }
+            },
+            '@' => {
+                let position = TokenPosition { row: cursor.position.row, col: cursor.position.col - 1 };
+                let mut function_name = String::new();
+                while let Some(c) = cursor.peek() && !c.is_whitespace() {
+                    function_name.push(c);
+                    cursor.next();
+                }
+                if function_name == name {
+                    return Some(position);
+                }
+            },
+            _ => (),
         }
+    }
+    None
+}
 
+/// What a piece of source code is, see `scan`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RawKind {
+    /// Anything else, eg. a number, a string, an operator or an identifier
+    Word,
+    Comment,
+    OpenBrace,
+    CloseBrace,
+}
 
-        let is_neg_num = character == '-' && code.peek().map_or(false, |&next_char| {
-            next_char.is_ascii_digit() || next_char == '.'
+/// A piece of source code exactly as it was written
+#[derive(Debug, Clone)]
+pub struct RawToken {
+    pub kind: RawKind,
+    pub text: String,
+    pub pos: TokenPosition,
+}
+
+/// Splits source code into pieces without changing or dropping any of it, for tools like the formatter
+/// 
+/// Nothing is checked apart from strings and blocks being closed, and libraries aren't loaded.
+/// 
+/// **Arguments:**
+/// * `code`: The source code to split
+/// 
+/// **Returns:** Every piece of code and comment in order, or every error found
+pub fn scan(code: &str) -> Result<Vec<RawToken>, Vec<TokenizerError>> {
+    let mut cursor = Cursor::new(code);
+    let mut tokens: Vec<RawToken> = Vec::new();
+    let mut errors = Vec::new();
+    let mut blocks = Vec::new();
+    loop {
+        let pos = cursor.position;
+        let Some(character) = cursor.next() else {
+            break;
+        };
+        if character.is_whitespace() {
+            continue;
+        }
+        //A library path is read up to the next whitespace, even if it starts like a comment
+        let after_use = tokens.last().is_some_and(|token| token.kind == RawKind::Word && token.text == "use");
+        let (kind, text) = match character {
+            ';' if !after_use => {
+                let mut text = String::from(';');
+                while let Some(c) = cursor.next() {
+                    text.push(c);
+                    if c == ';' {
+                        break;
+                    }
+                }
+                //A doc comment is an empty comment straight before another, which are kept together
+                if text == ";;" && cursor.peek() == Some(';') {
+                    cursor.next();
+                    text.push(';');
+                    while let Some(c) = cursor.next() {
+                        text.push(c);
+                        if c == ';' {
+                            break;
+                        }
+                    }
+                }
+                (RawKind::Comment, text)
+            },
+            '{' => {
+                blocks.push(pos);
+                (RawKind::OpenBrace, String::from('{'))
+            },
+            '}' => {
+                if blocks.pop().is_none() {
+                    errors.push(TokenizerError::BlockHadNoEnd(pos));
+                }
+                (RawKind::CloseBrace, String::from('}'))
+            },
+            _ => {
+                let mut text = String::from(character);
+                let mut in_string = character == '"' && !after_use;
+                while let Some(c) = cursor.peek() && (in_string || !c.is_whitespace()) {
+                    cursor.next();
+                    text.push(c);
+                    if in_string && c == '\\' && let Some(escaped) = cursor.next() {
+                        text.push(escaped);
+                    } else if c == '"' {
+                        in_string = false;
+                    }
+                }
+                if in_string {
+                    errors.push(TokenizerError::StringHadNoEnd(pos));
+                }
+                (RawKind::Word, text)
+            },
+        };
+        tokens.push(RawToken {kind, text, pos});
+    }
+    errors.extend(blocks.into_iter().map(TokenizerError::BlockHadNoEnd));
+    if errors.is_empty() {
+        Ok(tokens)
+    } else {
+        errors.sort_by_key(|error| {
+            let pos = error.position();
+            (pos.row, pos.col)
         });
+        Err(errors)
+    }
+}
+
+/// Finds the doc comments, like `;;; Adds two. ;`, written above function and tag definitions
+/// 
+/// Doc comments straight after each other are joined, and each line is trimmed. Any other code or
+/// comment between a doc comment and a definition stops it being attached.
+/// 
+/// **Arguments:**
+/// * `code`: The source code to look through
+/// 
+/// **Returns:** The doc text of each definition that has one, by name
+pub fn find_docs(code: &str) -> HashMap<String, String> {
+    let mut docs = HashMap::new();
+    let mut pending: Option<String> = None;
+    for token in scan(code).unwrap_or_default() {
+        if token.kind == RawKind::Comment && let Some(text) = token.text.strip_prefix(";;;") {
+            let text = text.strip_suffix(';').unwrap_or(text);
+            let lines: Vec<&str> = text.lines().map(str::trim).collect();
+            let text = lines.join("\n").trim().to_string();
+            pending = Some(match pending {
+                Some(before) => format!("{}\n{}", before, text),
+                None => text,
+            });
+            continue;
+        }
+        if let Some(doc) = pending.take() && token.kind == RawKind::Word && let Some(name) = token.text.strip_prefix('@') {
+            docs.insert(name.to_string(), doc);
+        }
+    }
+    docs
+}
+
+/// Finds the doc comments kept in the libraries the code uses, under the names they are imported as
+/// 
+/// Libraries that can't be found or read are skipped, the tokenizer reports those.
+/// 
+/// **Arguments:**
+/// * `code`: The source code to look through
+/// * `libraries`: Where `use` looks for libraries
+/// 
+/// **Returns:** The doc text of each imported definition that has one, by name
+pub fn find_library_docs(code: &str, libraries: &LibrarySearch) -> HashMap<String, String> {
+    let mut docs = HashMap::new();
+    let tokens = scan(code).unwrap_or_default();
+    for pair in tokens.windows(2) {
+        let [keyword, path] = pair else {
+            continue;
+        };
+        if keyword.kind != RawKind::Word || keyword.text != "use" || path.kind != RawKind::Word {
+            continue;
+        }
+        //What follows the path is read the same way the tokenizer reads it
+        let end = TokenPosition {row: path.pos.row, col: path.pos.col + path.text.chars().count()};
+        let mut cursor = Cursor::new(code);
+        while cursor.position != end && cursor.next().is_some() {}
+        let Ok((alias, selected)) = read_import(&mut cursor) else {
+            continue;
+        };
+        let Ok(content) = libraries.read(&format!("{}.stk.lib", path.text)) else {
+            continue;
+        };
+        let Ok((_, library_docs)) = read_library(&content) else {
+            continue;
+        };
+        for (name, doc) in library_docs {
+            if selected.as_ref().is_some_and(|selected| !selected.iter().any(|(_, wanted)| *wanted == name)) {
+                continue;
+            }
+            let name = match &alias {
+                Some(alias) => format!("{}.{}", alias, name),
+                None => name,
+            };
+            docs.insert(name, doc);
+        }
+    }
+    docs
+}
 
-        //handles numbers
-        if character.is_ascii_digit() || character == '.' || is_neg_num{
-            let start_pos = position;
+/// Where `use` looks for libraries, in order
+#[derive(Debug, Clone)]
+pub struct LibrarySearch {
+    directories: Vec<PathBuf>,
+}
 
-            let mut number_string = String::from(character);
-            let mut saw_dot = character == '.';
+impl LibrarySearch {
+    /// Looks next to the file doing the importing first, then in each directory in the `STACKATHON_PATH`
+    /// environment variable, then in `lib_paths`, and last in the working directory
+    /// 
+    /// **Arguments:**
+    /// * `file`: The path of the file doing the importing, if the code came from one
+    /// * `lib_paths`: More directories to look in, eg. from `--lib-path`
+    pub fn new(file: Option<&str>, lib_paths: &[PathBuf]) -> Self {
+        let mut directories = Vec::new();
+        //The parent of a file in the working directory is empty, which joins like the working directory
+        if let Some(parent) = file.and_then(|file| Path::new(file).parent()) {
+            directories.push(parent.to_path_buf());
+        }
+        if let Some(paths) = std::env::var_os("STACKATHON_PATH") {
+            directories.extend(std::env::split_paths(&paths).filter(|path| !path.as_os_str().is_empty()));
+        }
+        directories.extend(lib_paths.iter().cloned());
+        //Where libraries were looked for before, so paths written from the working directory still work
+        directories.push(PathBuf::new());
+        LibrarySearch {directories}
+    }
 
+    /// Reads a library from the first directory that has it
+    /// 
+    /// **Arguments:**
+    /// * `filename`: The library's path as it was written, with `.stk.lib` added
+    /// 
+    /// **Returns:** The library's contents, or every path that was tried
+    fn read(&self, filename: &str) -> Result<Vec<u8>, Vec<String>> {
+        let mut tried = Vec::new();
+        for directory in &self.directories {
+            let path = directory.join(filename);
+            if let Ok(content) = std::fs::read(&path) {
+                return Ok(content);
+            }
+            //An absolute path is the same from every directory
+            let path = path.display().to_string();
+            if !tried.contains(&path) {
+                tried.push(path);
+            }
+        }
+        Err(tried)
+    }
+}
 
-            position.col += 1;
+/// Reads the characters of source code, keeping track of where they are
+#[derive(Clone)]
+struct Cursor<'a> {
+    code: Peekable<Chars<'a>>,
+    /// The position of the next character
+    position: TokenPosition,
+}
 
-            while let Some(&next_character) = code.peek() {
-                if next_character.is_ascii_digit() {
-                    number_string.push(code.next().unwrap());
-                    position.col += 1;
-                } else if next_character == '.' {
+impl<'a> Cursor<'a> {
+    fn new(code: &'a str) -> Self {
+        Cursor { code: code.chars().peekable(), position: TokenPosition { row: 1, col: 1 } }
+    }
 
-                    if saw_dot {
-                        code.next();
-                        return Err(TokenizerError::InvalidNumberFormat(position));
-                    }
+    fn next(&mut self) -> Option<char> {
+        let character = self.code.next()?;
+        if character == '\n' {
+            self.position.row += 1;
+            self.position.col = 1;
+        } else {
+            self.position.col += 1;
+        }
+        Some(character)
+    }
 
-                    saw_dot = true;
-                    number_string.push(code.next().unwrap());
-                    position.col += 1;
-                } else if next_character.is_whitespace() {
-                    break;
-                } else {
+    fn peek(&mut self) -> Option<char> {
+        self.code.peek().copied()
+    }
 
-                    code.next();
-                    return Err(TokenizerError::InvalidNumberFormat(position));
-                }
-                
+    /// Most tokens have to be followed by whitespace, or the end of the code
+    fn expect_whitespace(&mut self) -> Result<(), TokenizerError> {
+        match self.peek() {
+            Some(c) if !c.is_whitespace() => Err(TokenizerError::UnexpectedSymbol(self.position, c)),
+            _ => Ok(()),
+        }
+    }
 
+    /// Skips to the next whitespace or brace, so tokenizing can carry on after an error
+    fn recover(&mut self) {
+        while let Some(c) = self.peek() {
+            if c.is_whitespace() || c == '{' || c == '}' {
+                break;
             }
-            let value = if saw_dot {
-                match number_string.parse::<f32>() {
-                    Ok(i) => Value::Float(i),
-                    //Never happens unless f32 is too small
-                    Err(_) => return Err(TokenizerError::InvalidNumberFormat(position)),
-                }
-            } else {
-                match number_string.parse::<i32>() {
-                    Ok(i) => Value::Integer(i),
-                    //Never happens unless i32 is too small
-                    Err(_) => return Err(TokenizerError::InvalidNumberFormat(position)),
-                }
-            };
-            
-
-            tokens.push(Token::new(TokenType::Literal(value), start_pos.row, start_pos.col));
-            continue;
+            self.next();
         }
-        //handles strings
-        if character == '"' {
-            let start_pos = position;
-            let mut string = String::new();
-            loop {
-                let next_character = match code.peek() {
-                    Some(&c) => c,
-                    None => return Err(TokenizerError::StringHadNoEnd(start_pos))
-                };
-                if next_character == '"' {
-                    
-                    position.col += 1;
-                    code.next();
-                    if let Some(&next_character) = code.peek() && !next_character.is_whitespace() {
-                        position.col += 1;
-                        return Err(TokenizerError::UnexpectedSymbol(position, next_character))
-                    }
-                    tokens.push(Token::new(TokenType::Literal(Value::String(string)), start_pos.row, start_pos.col));
+    }
+
+    /// Skips to the closing brace of the current block (or the end of the code) without tokenizing
+    fn skip_block(&mut self) {
+        let mut block_balancer = 0;
+        while let Some(c) = self.peek() {
+            if c == '}' {
+                if block_balancer == 0 {
                     break;
                 }
-                if next_character == '\\' {
-                   
-                    code.next();
-                    position.col += 1;
-                    match code.next() {
-                        Some('n') => string.push('\n'),
-                        Some('t') => string.push('\t'),
-                        Some('\\') => string.push('\\'),
-                        Some('"') => string.push('\"'),
-                        Some('r') => string.push('\r'),
-
-                        Some(other) => {
-                            string.push('\\');
-                            string.push(other);
-                        }
+                block_balancer -= 1;
+            }
+            if c == '{' {
+                block_balancer += 1;
+            }
+            self.next();
+        }
+    }
+}
 
-                        None => {
-                            string.push('\\');
-                        }
-                    }
-                    
-                    position.col += 1;
-                    continue;
-                }
+/// Tokenizes until the end of the code, or the end of the current block
+/// 
+/// **Arguments:**
+/// * `cursor`: Where to read the code from
+/// * `block_start`: The position of the opening brace, if this is a block
+/// * `functions`: The function table, which gets every function definition added to it
+/// * `permissions`: What the code is allowed to use
+/// * `libraries`: Where `use` looks for libraries
+/// * `errors`: Where to put any errors found
+fn tokenize_code(cursor: &mut Cursor, block_start: Option<TokenPosition>, functions: &mut HashMap<String, Vec<Token>>, permissions: &Permissions, libraries: &LibrarySearch, errors: &mut Vec<TokenizerError>) -> Vec<Token> {
+    
+    let mut tokens = Vec::new();
 
-                string.push(next_character);
-                position.col += 1;
-                code.next();
+    loop {
+        let position = cursor.position;
+        let character = match cursor.next() {
+            Some(c) => c,
+            None => {
+                if let Some(start) = block_start {
+                    errors.push(TokenizerError::BlockHadNoEnd(start));
+                }
+                return tokens;
             }
-            
-        }
-        //handles blocks
-        if character == '{' {
-            let p = position;
-            let token = handle_block(&mut position, &mut code, functions)?;
-            tokens.push(Token::new(TokenType::Literal(Value::Block(token)), p.row, p.col));
+        };
+
+        if character.is_whitespace() {
+            continue;
         }
 
         if character == '}' {
-            return Err(TokenizerError::BlockHadNoEnd(position))
-        }
-        //handles operators
-        if character == '+' {
-            let next_character = code.peek().map(|&c| c).unwrap_or(' ');
-            if !next_character.is_whitespace() {
-                position.col += 1;
-                return Err(TokenizerError::UnexpectedSymbol(position, next_character));
-            }
-            tokens.push(Token::new(TokenType::Op(Operation::Add),position.row, position.col));
-            
-        }
-        if character == '-' {
-            let next_character = code.peek().map(|&c| c).unwrap_or(' ');
-            if !next_character.is_whitespace() {
-                position.col += 1;
-                return Err(TokenizerError::UnexpectedSymbol(position, next_character));
-            }
-            tokens.push(Token::new(TokenType::Op(Operation::Subtract),position.row, position.col));
-        }
-        if character == '/' {
-            let next_character = code.peek().map(|&c| c).unwrap_or(' ');
-            if !next_character.is_whitespace() {
-                position.col += 1;
-                return Err(TokenizerError::UnexpectedSymbol(position, next_character));
-            }
-            tokens.push(Token::new(TokenType::Op(Operation::Divide),position.row, position.col));
-        }
-        if character == '*' {
-            let next_character = code.peek().map(|&c| c).unwrap_or(' ');
-            if !next_character.is_whitespace() {
-                position.col += 1;
-                return Err(TokenizerError::UnexpectedSymbol(position, next_character));
-            }
-            tokens.push(Token::new(TokenType::Op(Operation::Multiply),position.row, position.col));
-        }
-        if character == '=' {
-            let next_character = code.peek().map(|&c| c).unwrap_or(' ');
-            if !next_character.is_whitespace() {
-                position.col += 1;
-                return Err(TokenizerError::UnexpectedSymbol(position, next_character));
-            }
-            tokens.push(Token::new(TokenType::Op(Operation::Equal), position.row, position.col));
-        }
-        if character == '!' {
-            let mut next_character = code.peek().map(|&c| c).unwrap_or(' ');
-            if next_character == '=' {
-                code.next();
-                position.col += 1;
-                next_character = code.peek().map(|&c| c).unwrap_or(' ');
-                if !next_character.is_whitespace() {
-                    position.col += 1;
-                    return Err(TokenizerError::UnexpectedSymbol(position, next_character));
-                }
-                tokens.push(Token::new(TokenType::Op(Operation::NotEqual), position.row, position.col));
+            if block_start.is_none() {
+                errors.push(TokenizerError::BlockHadNoEnd(position));
                 continue;
             }
-            tokens.push(Token::new(TokenType::Op(Operation::Not), position.row, position.col));
-        }
-        if character == '<' {
-            let mut next_character = code.peek().map(|&c| c).unwrap_or(' ');
-            if next_character == '=' {
-                code.next();
-                position.col += 1;
-                next_character = code.peek().map(|&c| c).unwrap_or(' ');
-                if !next_character.is_whitespace() {
-                    position.col += 1;
-                    return Err(TokenizerError::UnexpectedSymbol(position, next_character));
-                }
-                tokens.push(Token::new(TokenType::Op(Operation::LesserEqual), position.row, position.col));
-                continue;
+            //A second closing brace straight after gets its own error
+            if cursor.peek() != Some('}') && let Err(error) = cursor.expect_whitespace() {
+                errors.push(error);
             }
-            tokens.push(Token::new(TokenType::Op(Operation::Lesser), position.row, position.col));
-        }
-        if character == '>' {
-            let mut next_character = code.peek().map(|&c| c).unwrap_or(' ');
-            if next_character == '=' {
-                code.next();
-                position.col += 1;
-                next_character = code.peek().map(|&c| c).unwrap_or(' ');
-                if !next_character.is_whitespace() {
-                    position.col += 1;
-                    return Err(TokenizerError::UnexpectedSymbol(position, next_character));
+            return tokens;
+        }
+
+        match read_token(cursor, character, position, functions, permissions, libraries, errors) {
+            Ok(Some(token)) => {
+                if let TokenType::Keyword(Keyword::EXIT) = token.kind {
+                    //Nothing after exit can run, so the rest of the block isn't tokenized
+                    cursor.skip_block();
+                    continue;
                 }
-                tokens.push(Token::new(TokenType::Op(Operation::GreaterEqual), position.row, position.col));
-                continue;
+                tokens.push(token);
+            },
+            Ok(None) => (),
+            Err(error) => {
+                errors.push(error);
+                cursor.recover();
             }
-            tokens.push(Token::new(TokenType::Op(Operation::Greater), position.row, position.col));
         }
-        if character == '&' {
-            let next_character = code.peek().map(|&c| c).unwrap_or(' ');
-            if !next_character.is_whitespace() {
-                position.col += 1;
-                return Err(TokenizerError::UnexpectedSymbol(position, next_character));
+    }
+}
+
+/// Reads the code for a block, after its opening brace
+fn read_block(cursor: &mut Cursor, start: TokenPosition, functions: &mut HashMap<String, Vec<Token>>, permissions: &Permissions, libraries: &LibrarySearch, errors: &mut Vec<TokenizerError>) -> Vec<Token> {
+    //The block is still read, so errors inside it are found too
+    if let Err(error) = cursor.expect_whitespace() {
+        errors.push(error);
+    }
+    tokenize_code(cursor, Some(start), functions, permissions, libraries, errors)
+}
+
+/// Reads the rest of a token
+/// 
+/// **Arguments:**
+/// * `cursor`: Where to read the code from
+/// * `character`: The first character of the token
+/// * `position`: The position of the first character
+/// * `functions`: The function table, which gets every function definition added to it
+/// * `permissions`: What the code is allowed to use
+/// * `libraries`: Where `use` looks for libraries
+/// * `errors`: Where to put errors found in blocks
+/// 
+/// **Returns:** The token, or `None` for things that aren't tokens, like comments and definitions
+fn read_token(cursor: &mut Cursor, character: char, position: TokenPosition, functions: &mut HashMap<String, Vec<Token>>, permissions: &Permissions, libraries: &LibrarySearch, errors: &mut Vec<TokenizerError>) -> Result<Option<Token>, TokenizerError> {
+
+    //handles comments
+    if character == ';' {
+        while let Some(c) = cursor.next() {
+            if c == ';' {
+                break;
             }
-            tokens.push(Token::new(TokenType::Op(Operation::And),position.row, position.col));
         }
-        if character == '|' {
-            let next_character = code.peek().map(|&c| c).unwrap_or(' ');
-            if !next_character.is_whitespace() {
-                position.col += 1;
-                return Err(TokenizerError::UnexpectedSymbol(position, next_character));
+        return Ok(None);
+    }
+
+    let is_neg_num = character == '-' && cursor.peek().is_some_and(|next_char| {
+        next_char.is_ascii_digit() || next_char == '.'
+    });
+
+    //handles numbers
+    if character.is_ascii_digit() || character == '.' || is_neg_num {
+        let mut number_string = String::from(character);
+        let mut saw_dot = character == '.';
+
+        while let Some(next_character) = cursor.peek() {
+            if next_character.is_ascii_digit() {
+                number_string.push(next_character);
+            } else if next_character == '.' && !saw_dot {
+                saw_dot = true;
+                number_string.push(next_character);
+            } else if next_character.is_whitespace() {
+                break;
+            } else {
+                return Err(TokenizerError::InvalidNumberFormat(cursor.position));
             }
-            tokens.push(Token::new(TokenType::Op(Operation::Or),position.row, position.col));
+            cursor.next();
         }
-        if character == '$' {
-            let next_character = code.peek().map(|&c| c).unwrap_or(' ');
-            if !next_character.is_whitespace() {
-                position.col += 1;
-                return Err(TokenizerError::UnexpectedSymbol(position, next_character));
+        let value = if saw_dot {
+            match number_string.parse::<f32>() {
+                Ok(i) => Value::Float(i),
+                //Happens for a lone '.'
+                Err(_) => return Err(TokenizerError::InvalidNumberFormat(position)),
             }
-            tokens.push(Token::new(TokenType::Op(Operation::Run),position.row, position.col));
-        }
+        } else {
+            match number_string.parse::<i32>() {
+                Ok(i) => Value::Integer(i),
+                //Never happens unless i32 is too small
+                Err(_) => return Err(TokenizerError::InvalidNumberFormat(position)),
+            }
+        };
+
+        return Ok(Some(Token::new(TokenType::Literal(value), position.row, position.col)));
+    }
 
-        //handles function def
-        if character == '@' {
-            position.col += 1;
-            let start_pos = position;
-            let mut function_name = String::new();
-            loop {
-                let next_char = match code.next() {
-                    Some(c) => c,
-                    None => break,
-                };
+    //handles strings
+    if character == '"' {
+        let mut string = String::new();
+        loop {
+            let next_character = match cursor.next() {
+                Some(c) => c,
+                None => return Err(TokenizerError::StringHadNoEnd(position))
+            };
+            if next_character == '"' {
+                cursor.expect_whitespace()?;
+                return Ok(Some(Token::new(TokenType::Literal(Value::String(string)), position.row, position.col)));
+            }
+            if next_character == '\\' {
+                match cursor.next() {
+                    Some('n') => string.push('\n'),
+                    Some('t') => string.push('\t'),
+                    Some('\\') => string.push('\\'),
+                    Some('"') => string.push('\"'),
+                    Some('r') => string.push('\r'),
+                    Some('{') => string.push('{'),
+                    Some('}') => string.push('}'),
+
+                    Some(other) => {
+                        string.push('\\');
+                        string.push(other);
+                    }
 
-                if next_char.is_whitespace() {
-                    break;
+                    None => {
+                        string.push('\\');
+                    }
                 }
+                continue;
+            }
 
-                if !(next_char.is_ascii_alphanumeric() || next_char == '_') {   
-                    return Err(TokenizerError::UnexpectedSymbol(position, next_char))
-                }
-                    
-                function_name.push(next_char);
-                position.col += 1;
+            string.push(next_character);
+        }
+    }
+
+    //handles blocks
+    if character == '{' {
+        let block = read_block(cursor, position, functions, permissions, libraries, errors);
+        return Ok(Some(Token::new(TokenType::Literal(Value::Block(block)), position.row, position.col)));
+    }
+
+    //handles operators
+    let operation = match character {
+        '+' => Some(Operation::Add),
+        '-' => Some(Operation::Subtract),
+        '/' => Some(Operation::Divide),
+        '*' => Some(Operation::Multiply),
+        '=' => Some(Operation::Equal),
+        '&' => Some(Operation::And),
+        '|' => Some(Operation::Or),
+        '$' => Some(Operation::Run),
+        '!' | '<' | '>' => {
+            let or_equal = cursor.peek() == Some('=');
+            if or_equal {
+                cursor.next();
+            }
+            Some(match (character, or_equal) {
+                ('!', true) => Operation::NotEqual,
+                ('!', false) => Operation::Not,
+                ('<', true) => Operation::LesserEqual,
+                ('<', false) => Operation::Lesser,
+                ('>', true) => Operation::GreaterEqual,
+                _ => Operation::Greater,
+            })
+        },
+        _ => None,
+    };
+    if let Some(operation) = operation {
+        cursor.expect_whitespace()?;
+        return Ok(Some(Token::new(TokenType::Op(operation), position.row, position.col)));
+    }
+
+    //handles function def
+    if character == '@' {
+        let start_pos = cursor.position;
+        let mut function_name = String::new();
+        loop {
+            match cursor.peek() {
+                None => break,
+                Some(c) if c.is_whitespace() => {
+                    cursor.next();
+                    break;
+                },
+                Some(c) if c.is_ascii_alphanumeric() || c == '_' => {
+                    function_name.push(c);
+                    cursor.next();
+                },
+                Some(c) => return Err(TokenizerError::UnexpectedSymbol(cursor.position, c)),
             }
-            if functions.contains_key(&function_name) {
-                return Err(TokenizerError::FunctionHasMultipleDefinitions(start_pos, function_name));
+        }
+        let defined = functions.contains_key(&function_name);
+        if defined {
+            errors.push(TokenizerError::FunctionHasMultipleDefinitions(start_pos, function_name.clone()));
+        }
+        let signature = if cursor.peek() == Some('(') {
+            let signature = match read_signature(cursor, function_name.clone()) {
+                Ok(signature) => signature,
+                Err(error) => {
+                    //Skips the rest of the declaration, so its words aren't read as code
+                    while let Some(c) = cursor.next() && c != ')' && c != '\n' {}
+                    return Err(error);
+                },
+            };
+            //A tag has no body to declare an effect for
+            while cursor.peek().is_some_and(|c| c.is_whitespace()) {
+                cursor.next();
             }
-            position.col += 1;
-            let c = code.next().unwrap_or(' ');
-            if c != '{' {
-               functions.insert(function_name, Vec::new());
-            } else {
+            if cursor.peek() != Some('{') {
+                return Err(TokenizerError::InvalidSignature(position, format!("'{}' declares a stack effect, but has no body", function_name)));
+            }
+            Some(Token::new(TokenType::Signature(signature), position.row, position.col))
+        } else {
+            None
+        };
+        if cursor.peek() != Some('{') {
+            if !defined {
+                functions.insert(function_name, Vec::new());
+            }
+        } else {
+            let block_start = cursor.position;
+            cursor.next();
+            if !defined {
                 functions.insert(function_name.clone(), Vec::new());
-                let definition = handle_block(&mut position, &mut code, functions)?;
-                functions.insert(function_name, definition);
+            }
+            let definition = read_block(cursor, block_start, functions, permissions, libraries, errors);
+            if !defined {
+                functions.insert(function_name, signature.into_iter().chain(definition).collect());
             }
         }
+        return Ok(None);
+    }
 
-        //handles keywords and identifiers
-        if character.is_ascii_alphabetic() || character == '_' {
-            let starting_position = position;
-            let mut ident = String::from(character);
-            while let Some(&next_character) = code.peek() {
-                if next_character.is_whitespace() {
-                    break;
-                }
-                if !next_character.is_ascii_alphanumeric() && next_character != '_' {
-                    return Err(TokenizerError::UnexpectedSymbol(position, next_character));
-                }
-                ident.push(code.next().unwrap());
-                position.col += 1;
-            }
-            let keyword_map = get_keywords();
-            //We don't jump directly to a token type because of 'true' and 'false' keywords.
-            let keyword =  match keyword_map.get(ident.as_str()) {
-                Some(key) => key.clone(),
-                None => {
-                    if functions.contains_key(&ident) {
-                        tokens.push(Token::new(TokenType::Literal(Value::Function(ident)),starting_position.row, starting_position.col));
-                        continue;
-                    } else {
-                        return Err(TokenizerError::UnknownIdentifier(starting_position, ident));
-                    }
-                }
-            };
-            if let Keyword::TRUE = keyword {
-                tokens.push(Token::new(TokenType::Literal(Value::Boolean(true)), starting_position.row, starting_position.col));
-                
-                continue;
+    //handles keywords and identifiers
+    if character.is_ascii_alphabetic() || character == '_' {
+        let mut ident = String::from(character);
+        while let Some(next_character) = cursor.peek() {
+            if next_character.is_whitespace() {
+                break;
             }
-            if let Keyword::FALSE = keyword {
-                tokens.push(Token::new(TokenType::Literal(Value::Boolean(false)), starting_position.row, starting_position.col));
-                
-                continue;
+            //A function from a library imported with `as` is used through its alias, eg. `m.foo`
+            if !next_character.is_ascii_alphanumeric() && next_character != '_' && next_character != '.' {
+                return Err(TokenizerError::UnexpectedSymbol(cursor.position, next_character));
             }
-            if let Keyword::EXIT = keyword {
-                return Ok(tokens)
-            }
-            if let Keyword::USE = keyword {
-                position.col += 1;
-                let character = match code.next() {
-                    Some(c) => c,
-                    None => return Err(TokenizerError::UnknownLibrary(position, "".to_string()))
-                };
-                if !character.is_whitespace() {
-                    return Err(TokenizerError::UnexpectedSymbol(position, character));
-                }
-                let starting_position = TokenPosition { row: position.row, col: position.col + 1 };
-                let mut filename = String::new();
-                while let Some(&character) = code.peek() {
-                    if character.is_whitespace() {
-                        break;
-                    }
-                    filename.push(code.next().unwrap()); //Unwrap is okay because we peeked first
-                    position.col += 1;
+            ident.push(next_character);
+            cursor.next();
+        }
+        let keyword_map = get_keywords();
+        //We don't jump directly to a token type because of 'true' and 'false' keywords.
+        let keyword =  match keyword_map.get(ident.as_str()) {
+            Some(key) => key.clone(),
+            None => {
+                //Checked once all the functions are defined
+                return Ok(Some(Token::new(TokenType::Literal(Value::Function(ident)), position.row, position.col)));
+            }
+        };
+        if let Keyword::TRUE = keyword {
+            return Ok(Some(Token::new(TokenType::Literal(Value::Boolean(true)), position.row, position.col)));
+        }
+        if let Keyword::FALSE = keyword {
+            return Ok(Some(Token::new(TokenType::Literal(Value::Boolean(false)), position.row, position.col)));
+        }
+        if let Keyword::USE = keyword {
+            if !permissions.is_allowed(Capability::LibraryLoading) {
+                return Err(TokenizerError::PermissionDenied(position, Capability::LibraryLoading));
+            }
+            match cursor.peek() {
+                Some(c) if c.is_whitespace() => {
+                    cursor.next();
+                },
+                Some(c) => return Err(TokenizerError::UnexpectedSymbol(cursor.position, c)),
+                None => return Err(TokenizerError::UnknownLibrary(cursor.position, "".to_string(), Vec::new())),
+            }
+            let starting_position = cursor.position;
+            let mut filename = String::new();
+            while let Some(character) = cursor.peek() {
+                if character.is_whitespace() {
+                    break;
                 }
-                filename.push_str(".stk.lib");
-                let library_content = match std::fs::read(&filename) {
-                    Ok(s) => s,
-                    Err(_) => return Err(TokenizerError::UnknownLibrary(starting_position, filename))
-                };
+                filename.push(character);
+                cursor.next();
+            }
+            filename.push_str(".stk.lib");
+            let (alias, selected) = read_import(cursor)?;
+            let library_content = match libraries.read(&filename) {
+                Ok(s) => s,
+                Err(tried) => return Err(TokenizerError::UnknownLibrary(starting_position, filename, tried))
+            };
 
-                let library_functions = match get_lib_hashmap(&library_content) {
-                    Ok(t) => t,
-                    Err(e) => return Err(TokenizerError::InvalidLibrary(starting_position, filename, e))
-                };
+            let (library_functions, _) = match read_library(&library_content) {
+                Ok(t) => t,
+                Err(e) => return Err(TokenizerError::InvalidLibrary(starting_position, filename, e))
+            };
 
-                functions.extend(library_functions);
+            import(functions, library_functions, &filename, alias.as_deref(), selected.as_deref(), starting_position)?;
 
-                continue;
-            }
-            tokens.push(Token::new(TokenType::Keyword(keyword), starting_position.row, starting_position.col));
+            return Ok(None);
         }
+        return Ok(Some(Token::new(TokenType::Keyword(keyword), position.row, position.col)));
+    }
 
+    Err(TokenizerError::UnexpectedSymbol(position, character))
+}
 
-        if character == '\n' {
-            position.row += 1;
-            position.col = 1;
-        } else {
-            position.col += 1;
+/// How a library is imported: the alias given with `as`, and the names listed to import with where each was written
+type Import = (Option<String>, Option<Vec<(TokenPosition, String)>>);
+
+/// Reads what can follow a library's path, `as <alias>` and then `( <names>... )`, which are both optional
+fn read_import(cursor: &mut Cursor) -> Result<Import, TokenizerError> {
+    //`as` is only a word here, so it is looked for without moving past code that follows the `use`
+    let mut ahead = cursor.clone();
+    skip_whitespace(&mut ahead);
+    let as_start = ahead.position;
+    let mut after_word = ahead.clone();
+    let mut word = String::new();
+    while let Some(c) = after_word.peek() && !c.is_whitespace() {
+        word.push(c);
+        after_word.next();
+    }
+    let mut alias = None;
+    if word == "as" {
+        *cursor = after_word;
+        //The alias has to be on the same line, and start like a function name
+        while cursor.peek().is_some_and(|c| c.is_whitespace() && c != '\n') {
+            cursor.next();
         }
-        
+        if !cursor.peek().is_some_and(|c| c.is_ascii_alphabetic() || c == '_') {
+            return Err(TokenizerError::InvalidImport(as_start, "'as' needs a name after it".to_string()));
+        }
+        let mut name = String::new();
+        while let Some(c) = cursor.peek() && !c.is_whitespace() && c != '(' {
+            if !c.is_ascii_alphanumeric() && c != '_' {
+                return Err(TokenizerError::UnexpectedSymbol(cursor.position, c));
+            }
+            name.push(c);
+            cursor.next();
+        }
+        alias = Some(name);
+        ahead = cursor.clone();
+        skip_whitespace(&mut ahead);
+    }
+    if ahead.peek() != Some('(') {
+        return Ok((alias, None));
     }
 
+    *cursor = ahead;
+    let start = cursor.position;
+    cursor.next();
+    let mut names = Vec::new();
+    loop {
+        skip_whitespace(cursor);
+        let name_start = cursor.position;
+        match cursor.peek() {
+            None => return Err(TokenizerError::InvalidImport(start, "the list of names has no closing ')'".to_string())),
+            Some(')') => {
+                cursor.next();
+                break;
+            },
+            _ => (),
+        }
+        let mut name = String::new();
+        while let Some(c) = cursor.peek() && !c.is_whitespace() && c != ')' {
+            if !c.is_ascii_alphanumeric() && c != '_' {
+                return Err(TokenizerError::UnexpectedSymbol(cursor.position, c));
+            }
+            name.push(c);
+            cursor.next();
+        }
+        names.push((name_start, name));
+    }
+    cursor.expect_whitespace()?;
+    if names.is_empty() {
+        return Err(TokenizerError::InvalidImport(start, "the list of names is empty".to_string()));
+    }
+    Ok((alias, Some(names)))
+}
 
-    Ok(tokens)
+fn skip_whitespace(cursor: &mut Cursor) {
+    while cursor.peek().is_some_and(char::is_whitespace) {
+        cursor.next();
+    }
 }
 
-fn handle_block(position: &mut TokenPosition, code: &mut Peekable<Chars>, functions: &mut HashMap<String, Vec<Token>>) -> Result<Vec<Token>, TokenizerError> {
-    if let Some(c) = code.next() && !c.is_whitespace() {
-        position.col += 1;
-        return Err(TokenizerError::UnexpectedSymbol(*position, c))
+/// Adds a library's functions and tags to the function table, under the names they are imported as
+/// 
+/// Functions that weren't imported by name are still added for the ones that use them, under a name
+/// that code can't write, like `my_lib.stk.lib#helper`.
+/// 
+/// **Arguments:**
+/// * `functions`: The function table
+/// * `library`: The library's function table
+/// * `path`: The library's path, for errors and the names of functions that weren't imported
+/// * `alias`: The name given with `as`, which the library's functions are used through, eg. `m.foo`
+/// * `selected`: The functions and tags imported by name, with where each was written
+/// * `pos`: Where the library's path was written
+fn import(functions: &mut HashMap<String, Vec<Token>>, library: HashMap<String, Vec<Token>>, path: &str, alias: Option<&str>, selected: Option<&[(TokenPosition, String)]>, pos: TokenPosition) -> Result<(), TokenizerError> {
+    if let Some(selected) = selected && let Some((name_pos, name)) = selected.iter().find(|(_, name)| !library.contains_key(name)) {
+        return Err(TokenizerError::InvalidImport(*name_pos, format!("'{}' has no function or tag '{}'", path, name)));
     }
-    position.col += 1;
-    let starting_position = *position;
-    let mut inner_code = String::new();
-    let mut block_balancer = 1;
-    loop { 
-        let character = match code.next() {
-            Some(c) => c,
-            None => {return Err(TokenizerError::BlockHadNoEnd(*position));}
+    let renamed: HashMap<String, String> = library.keys().map(|name| {
+        let imported = selected.is_none_or(|selected| selected.iter().any(|(_, wanted)| wanted == name));
+        let new_name = match (imported, alias) {
+            (false, _) => format!("{}{}{}", path, HIDDEN, name),
+            (true, Some(alias)) => format!("{}.{}", alias, name),
+            (true, None) => name.clone(),
         };
+        (name.clone(), new_name)
+    }).collect();
+    let mut library: Vec<(String, Vec<Token>)> = library.into_iter()
+        .map(|(name, mut body)| {
+            rename(&mut body, &renamed);
+            (renamed[&name].clone(), body)
+        })
+        .collect();
+    library.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+    //The same function can come in twice, eg. from two libraries that both use a third
+    let conflict = library.iter().find(|(name, body)| functions.get(name).is_some_and(|existing| !same_code(existing, body)));
+    if let Some((name, _)) = conflict {
+        return Err(TokenizerError::ImportConflict(pos, path.to_string(), name.clone()));
+    }
+    functions.extend(library);
+    Ok(())
+}
 
-        if character == '}' {
-            position.col += 1;
-            let c = code.next().unwrap_or(' ');
-            if !c.is_whitespace() {
-                position.col += 1;
-                return Err(TokenizerError::UnexpectedSymbol(*position, c))
-            }
-            block_balancer -= 1;
-            if block_balancer == 0 {
-                inner_code.push('\0');
-                position.col += 1;
-                break;
-            } else if block_balancer < 0 {
-                return Err(TokenizerError::BlockHadNoEnd(*position))
-            }
+/// Changes the functions and tags that code uses to the names they were imported as
+fn rename(tokens: &mut [Token], renamed: &HashMap<String, String>) {
+    for token in tokens {
+        match &mut token.kind {
+            TokenType::Literal(Value::Function(name)) => if let Some(new_name) = renamed.get(name) {
+                *name = new_name.clone();
+            },
+            TokenType::Literal(Value::Block(block)) => rename(block, renamed),
+            TokenType::Signature(signature) => if let Some(new_name) = renamed.get(&signature.name) {
+                signature.name = new_name.clone();
+            },
+            _ => (),
         }
+    }
+}
 
-        if character == '{' {
-            block_balancer += 1;
-        }
+fn same_code(a: &[Token], b: &[Token]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.to_bytes() == b.to_bytes())
+}
 
-        if character == '\\' {
-            if let Some(c) = code.next() {
-                if c == '{' || c == '}' {
-                    inner_code.push(c);
-                    position.col += 2;
-                    continue;
-                } else {
-                    inner_code.push('\\');
-                    inner_code.push(c);
-                    position.col += 2;
-                    continue;
-                }
-            } 
-        }
+/// Whether a function is one a library needs but wasn't imported by name, so code can't use it
+pub fn is_hidden(name: &str) -> bool {
+    name.contains(HIDDEN)
+}
 
-        inner_code.push(character);
-        if character == '\n' {
-            position.row += 1;
-            position.col = 0;
-        } else {
-            position.col += 1;
+/// Reads a stack effect declaration like `( a int -- string )`, starting at its opening parenthesis
+/// 
+/// **Arguments:**
+/// * `cursor`: Where to read the code from
+/// * `name`: The function the stack effect is for
+fn read_signature(cursor: &mut Cursor, name: String) -> Result<Signature, TokenizerError> {
+    let start = cursor.position;
+    cursor.next();
+    cursor.expect_whitespace()?;
+    let mut inputs = Vec::new();
+    let mut outputs = None;
+    loop {
+        while cursor.peek().is_some_and(|c| c.is_whitespace()) {
+            cursor.next();
+        }
+        let word_start = cursor.position;
+        let mut word = String::new();
+        while let Some(c) = cursor.peek() && !c.is_whitespace() {
+            if !c.is_ascii_alphanumeric() && c != '_' && c != '-' && c != ')' {
+                return Err(TokenizerError::UnexpectedSymbol(cursor.position, c));
+            }
+            word.push(c);
+            cursor.next();
         }
+        match word.as_str() {
+            "" => return Err(TokenizerError::InvalidSignature(start, "it has no closing ')'".to_string())),
+            ")" => break,
+            "--" if outputs.is_none() => outputs = Some(Vec::new()),
+            "--" => return Err(TokenizerError::InvalidSignature(word_start, "it has more than one '--'".to_string())),
+            _ if word.contains('-') || word.contains(')') => {
+                let bad = word.find(['-', ')']).unwrap_or(0);
+                return Err(TokenizerError::UnexpectedSymbol(TokenPosition { row: word_start.row, col: word_start.col + bad }, word.as_bytes()[bad] as char));
+            },
+            _ => match &mut outputs {
+                Some(outputs) => outputs.push(word),
+                None => inputs.push(word),
+            },
+        }
+    }
+    cursor.expect_whitespace()?;
+    match outputs {
+        Some(outputs) => Ok(Signature {name, inputs, outputs}),
+        None => Err(TokenizerError::InvalidSignature(start, "it needs '--' between its inputs and outputs".to_string())),
     }
-    return Ok(tokenize(&inner_code, Some(starting_position), functions)?)
 }
 
-
-fn get_keywords() -> &'static HashMap<&'static str, Keyword> {
+pub fn get_keywords() -> &'static HashMap<&'static str, Keyword> {
     KEYWORDS.get_or_init(|| {
 
         let mut map = HashMap::new();
@@ -653,11 +1251,28 @@ fn get_keywords() -> &'static HashMap<&'static str, Keyword> {
         map.insert("use", Keyword::USE);
         map.insert("input", Keyword::INPUT);
         map.insert("strlen", Keyword::STRLEN);
+        map.insert("assert", Keyword::ASSERT);
+        map.insert("read", Keyword::READ);
+        map.insert("readlines", Keyword::READLINES);
+        map.insert("write", Keyword::WRITE);
+        map.insert("append", Keyword::APPEND);
+        map.insert("exists", Keyword::EXISTS);
+        map.insert("delete", Keyword::DELETE);
+        map.insert("try", Keyword::TRY);
+        map.insert("args", Keyword::ARGS);
+        map.insert("env", Keyword::ENV);
+        map.insert("break", Keyword::BREAK);
         map
     })
 }
 
-fn get_lib_hashmap(content: &[u8]) -> Result<HashMap<String, Vec<Token>>, SerializationError> {
+/// A library's function table, and the doc text of each definition that has one
+pub type Library = (HashMap<String, Vec<Token>>, HashMap<String, String>);
+
+/// Reads the function table and doc comments out of a library file
+/// 
+/// Libraries from before doc comments were kept have none.
+pub fn read_library(content: &[u8]) -> Result<Library, SerializationError> {
     if content.len() < 12 { //The header length
         return Err(SerializationError::EndOfFile);
     };
@@ -717,5 +1332,30 @@ fn get_lib_hashmap(content: &[u8]) -> Result<HashMap<String, Vec<Token>>, Serial
 
     }
 
-    return Ok(functions);
+    let mut docs = HashMap::new();
+    if library_version >= DOCS_VERSION {
+        let doc_count = read_u32(content, &mut offset)?;
+        for _ in 0..doc_count {
+            let name = read_text(content, &mut offset)?;
+            let doc = read_text(content, &mut offset)?;
+            docs.insert(name, doc);
+        }
+    }
+
+    return Ok((functions, docs));
+}
+
+/// Reads a big endian number from a library, moving past it
+fn read_u32(content: &[u8], offset: &mut usize) -> Result<u32, SerializationError> {
+    let bytes = content.get(*offset..*offset + 4).ok_or(SerializationError::EndOfFile)?;
+    *offset += 4;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap())) //Unwrap okay because the slice is 4 long
+}
+
+/// Reads a length and then that many bytes of UTF-8 from a library, moving past them
+fn read_text(content: &[u8], offset: &mut usize) -> Result<String, SerializationError> {
+    let length = read_u32(content, offset)? as usize;
+    let bytes = content.get(*offset..*offset + length).ok_or(SerializationError::EndOfFile)?;
+    *offset += length;
+    String::from_utf8(bytes.to_vec()).map_err(SerializationError::InvalidUTF8Encoding)
 }
@@ -14,17 +14,52 @@
 //! run_string("\"hello \" \"world\" + print".to_string());
 //! //Expected Output: "hello world"
 //! ```
+//! ## Tracing
+//! Implement `Tracer` to follow code as it runs, eg. to count how often each token is run.
+//! ```
+//! use std::{cell::RefCell, collections::HashMap, fmt::Display, rc::Rc};
+//! use stackathon::{Context, Stack, TokenPosition, Tracer, run_string_with_context};
+//! 
+//! #[derive(Default)]
+//! struct Counter(HashMap<String, usize>);
+//! 
+//! impl Tracer for Counter {
+//!     fn before(&mut self, token: &dyn Display, _pos: TokenPosition, _stack: &Stack) -> bool {
+//!         *self.0.entry(token.to_string()).or_default() += 1;
+//!         true
+//!     }
+//! }
+//! 
+//! let counter = Rc::new(RefCell::new(Counter::default()));
+//! let mut context = Context::new();
+//! context.tracer = Some(counter.clone());
+//! run_string_with_context("1 2 + 3 + drop".to_string(), &mut context);
+//! assert_eq!(counter.borrow().0["+"], 2);
+//! ```
 //! ## Modules
 //! * 'lexer': Handles tokenizing valid stackathon source code
 //! * 'vm': Handles running the tokens given by the lexer
 //! * 'types': Defines types used throughout the library
 //! * 'serial': Handles serializing libraries efficiently
+//! * 'diagnostic': Shows errors with the code they are about
+//! * 'checker': Finds stack underflows and type errors without running the code
+//! * 'formatter': Lays out source code consistently
+//! * 'linter': Warns about common mistakes
+//! * 'json': Reads and writes the JSON used by editors
+//! * 'lsp': Talks to editors over the Language Server Protocol
+//! * 'debugger': Pauses running code to show the stack and take commands
+//! * 'trace': Logs every token as it runs
+//! * 'profile': Measures where the time goes when code runs
+//! * 'coverage': Records which code ran, for coverage reports
+//! * 'doc': Writes reference pages from doc comments
 
 
 
-use std::{collections::HashMap, fs::File, io::{BufWriter, Write}};
+use std::{cell::RefCell, collections::{HashMap, HashSet}, fs::File, io::{BufRead, BufWriter, Write}, rc::Rc};
 
-use crate::{lexer::{Token, tokenize}, serial::ByteSized, vm::execute};
+use crate::{checker::check, debugger::Debugger, diagnostic::{Diagnostic, print_file_error}, doc::{entries, render}, formatter::format, linter::lint, lexer::{LibrarySearch, Token, TokenType, TokenizerError, find_definition, find_docs, is_hidden, read_library, tokenize}, serial::ByteSized, vm::{RuntimeError, execute}};
+
+pub use crate::{coverage::Coverage, diagnostic::{ColorChoice, ErrorFormat}, doc::DocFormat, lexer::TokenPosition, linter::{Lint, Lints}, profile::Profiler, trace::TraceLog, types::{Capability, Permissions, Value}, vm::{Context, Stack, Tracer}};
 
 
 
@@ -32,6 +67,17 @@ mod lexer;
 mod vm;
 mod types;
 mod serial;
+mod diagnostic;
+mod checker;
+mod formatter;
+mod linter;
+mod json;
+mod lsp;
+mod debugger;
+mod trace;
+mod profile;
+mod coverage;
+mod doc;
 
 /// Used for libraries
 /// 
@@ -40,21 +86,33 @@ mod serial;
 /// `2.3.4` becomes `234`.
 /// 
 /// `0.3.5` becomes `35`.
-const VERSION: u32 = 50;
+const VERSION: u32 = 52;
+
+/// The first library version with doc comments, which come after the function table
+const DOCS_VERSION: u32 = 52;
 
 ///Used when turning a stackathon file into a lib file
 /// 
 /// **Arguments**
 /// * `filepath`: The stackathon file to compile
 pub fn compile_file(filepath: &str) {
+    compile_file_with_context(filepath, &Context::new());
+}
+
+///Used when turning a stackathon file into a lib file with custom settings
+/// 
+/// **Arguments**
+/// * `filepath`: The stackathon file to compile
+/// * `context`: The settings to compile with, eg. how errors are shown
+pub fn compile_file_with_context(filepath: &str, context: &Context) {
     let source = match std::fs::read_to_string(filepath) {
         Ok(s) => s,
         Err(e) => {
-            eprintln!("Error reading file '{}': {}", filepath, e);
+            print_file_error("E0001", &format!("Error reading file '{}': {}", filepath, e), None, filepath, context);
             return;
         }
     };
-    compile_string(source, filepath);
+    compile_source(source, filepath, Some(filepath), context);
 }
 
 ///Used when turning a stackathon code into a lib file
@@ -63,16 +121,29 @@ pub fn compile_file(filepath: &str) {
 /// * `source`: The stackathon source code to compile
 /// * `libname`: The name of the new lib file
 pub fn compile_string(source: String, libname: &str) {
+    compile_source(source, libname, None, &Context::new());
+}
+
+/// Turns stackathon code into a lib file
+/// 
+/// **Arguments**
+/// * `source`: The stackathon source code to compile
+/// * `libname`: The name of the new lib file
+/// * `file`: The path the source came from, for errors
+/// * `context`: The settings to compile with
+fn compile_source(source: String, libname: &str, file: Option<&str>, context: &Context) {
     let mut functions: HashMap<String, Vec<Token>> = HashMap::new();
 
-    if let Err(error) = tokenize(&source, None, &mut functions) {
-       
-        let pos = error.position();
-        print_error(&source, &error.to_string(), pos.row, pos.col);
+    if let Err(errors) = tokenize(&source, &mut functions, &context.permissions, &LibrarySearch::new(file, &context.lib_paths)) {
+        print_errors(&source, file, &errors, context);
         return;
-    
     }
 
+    //Library functions don't have their doc comments in this file
+    let docs: Vec<(String, String)> = find_docs(&source).into_iter()
+        .filter(|(name, _)| functions.contains_key(name))
+        .collect();
+
     //Instead of executing the code, we serialze the function table.
 
     let filename = libname.to_string() + ".lib";
@@ -120,6 +191,17 @@ pub fn compile_string(source: String, libname: &str) {
         }
     }
 
+    //How many doc comments there are (4 bytes)
+    buffer.extend_from_slice(&(docs.len() as u32).to_be_bytes());
+    for (name, doc) in docs {
+        //length of the name (4 bytes), then the name
+        buffer.extend_from_slice(&(name.len() as u32).to_be_bytes());
+        buffer.extend_from_slice(name.as_bytes());
+        //length of the doc text (4 bytes), then the text
+        buffer.extend_from_slice(&(doc.len() as u32).to_be_bytes());
+        buffer.extend_from_slice(doc.as_bytes());
+    }
+
     if let Err(error) = writer.write_all(&buffer) {
         eprintln!("Error writing library to file: {}", error);
         return;
@@ -138,18 +220,63 @@ pub fn compile_string(source: String, libname: &str) {
 /// **Arguments:**
 /// * `filepath`: The path to the source file to run
 pub fn run_file(filepath: &str) {
+    run_file_with_context(filepath, &mut Context::new());
+}
+
+/// Used when running stackthon code from a file with custom settings
+/// 
+/// **Arguments:**
+/// * `filepath`: The path to the source file to run
+/// * `context`: The settings to run the code with, eg. the script's arguments
+pub fn run_file_with_context(filepath: &str, context: &mut Context) {
     
     let source = match std::fs::read_to_string(filepath) {
         Ok(s) => s,
         Err(e) => {
-            eprintln!("Error reading file '{}': {}", filepath, e);
+            print_file_error("E0001", &format!("Error reading file '{}': {}", filepath, e), None, filepath, context);
             return;
         }
     };
 
-    run_string(source);
-    
+    run_source(source, Some(filepath), true, context);
+}
+
+/// Used when stepping through a stackathon file in the debugger
+/// 
+/// It pauses before the first token, and commands are read from stdin.
+/// 
+/// **Arguments:**
+/// * `filepath`: The path to the source file to debug
+pub fn debug_file(filepath: &str) {
+    debug_file_with_context(filepath, &mut Context::new());
+}
 
+/// Used when stepping through a stackathon file in the debugger with custom settings
+/// 
+/// **Arguments:**
+/// * `filepath`: The path to the source file to debug
+/// * `context`: The settings to run the code with, eg. the script's arguments
+pub fn debug_file_with_context(filepath: &str, context: &mut Context) {
+    let source = match std::fs::read_to_string(filepath) {
+        Ok(s) => s,
+        Err(e) => {
+            print_file_error("E0001", &format!("Error reading file '{}': {}", filepath, e), None, filepath, context);
+            return;
+        }
+    };
+
+    let mut functions = HashMap::new();
+    //Errors are shown when the code is run
+    let _ = tokenize(&source, &mut functions, &context.permissions, &LibrarySearch::new(Some(filepath), &context.lib_paths));
+    //Tags have no body, so they are never called
+    let names = functions.into_iter()
+        .filter(|(name, body)| !is_hidden(name) && !body.is_empty())
+        .map(|(name, _)| name)
+        .collect();
+    context.tracer = Some(Rc::new(RefCell::new(Debugger::new(&source, names))));
+    //Quitting the debugger stops the program, which isn't an error
+    run_source(source, Some(filepath), false, context);
+    context.tracer = None;
 }
 
 /// Used when running stackathon code from a string
@@ -157,14 +284,63 @@ pub fn run_file(filepath: &str) {
 /// **Arguments:**
 /// * `source`: The stackathon source code to run
 pub fn run_string(source: String) {
+    run_string_with_context(source, &mut Context::new());
+}
+
+/// Used when running stackathon code from a string with custom settings
+/// 
+/// **Arguments:**
+/// * `source`: The stackathon source code to run
+/// * `context`: The settings to run the code with, eg. the maximum call depth
+/// 
+/// Limits make it safe to run code that might never finish:
+/// ```
+/// use std::time::Duration;
+/// use stackathon::{Context, run_string_with_context};
+/// 
+/// let mut context = Context::new();
+/// context.max_steps = Some(10_000);
+/// context.max_memory = Some(1024 * 1024);
+/// context.timeout = Some(Duration::from_secs(1));
+/// 
+/// run_string_with_context("true { true } loop".to_string(), &mut context);
+/// //Expected Output: Runtime Error[E0207](1:8): Step limit of 10000 exceeded.
+/// 
+/// //The limits are counted again for each run
+/// run_string_with_context("1 print".to_string(), &mut context);
+/// //Expected Output: 1
+/// ```
+/// 
+/// Permissions stop code from touching anything outside of the interpreter:
+/// ```
+/// use stackathon::{Capability, Context, Permissions, run_string_with_context};
+/// 
+/// let mut context = Context::new();
+/// context.permissions = Permissions::none();
+/// context.permissions.allow(Capability::Stdout);
+/// 
+/// run_string_with_context("input print".to_string(), &mut context);
+/// //Expected Output: Runtime Error[E0211](1:1): Permission denied, the stdin capability is not allowed.
+/// ```
+pub fn run_string_with_context(source: String, context: &mut Context) {
+    run_source(source, None, true, context);
+}
+
+/// Runs stackathon code, printing any errors
+/// 
+/// **Arguments:**
+/// * `source`: The stackathon source code to run
+/// * `file`: The path the source came from, for errors
+/// * `show_stopped`: Whether to show a `Stopped` error, from the tracer stopping the program
+/// * `context`: The settings to run the code with
+fn run_source(source: String, file: Option<&str>, show_stopped: bool, context: &mut Context) {
     let mut functions: HashMap<String, Vec<Token>> = HashMap::new();
 
-    let tokens = match tokenize(&source, None, &mut functions) {
+    let tokens = match tokenize(&source, &mut functions, &context.permissions, &LibrarySearch::new(file, &context.lib_paths)) {
         Ok(t) => t,
 
-        Err(error) => {
-            let pos = error.position();
-            print_error(&source, &error.to_string(), pos.row, pos.col);
+        Err(errors) => {
+            print_errors(&source, file, &errors, context);
             return;
         }
     };
@@ -172,26 +348,557 @@ pub fn run_string(source: String) {
     //println!("Tokenized with tokens {:#?}", tokens);
 
     let mut stack = vm::Stack::new();
-    if let Err(e) = execute(&tokens, &mut stack, &functions) {
-        let pos = e.position();
-        print_error(&source, &e.to_string(), pos.row, pos.col);
+    context.reset();
+    match execute(&tokens, &mut stack, &functions, context) {
+        Ok(()) => (),
+        Err(RuntimeError::Stopped(_)) if !show_stopped => (),
+        Err(e) => Diagnostic::from(&e).print(&source, file, context),
     }
 }
 
+/// Used when running the tests in a stackathon file.
+/// 
+/// **Arguments:**
+/// * `filepath`: The path to the source file to test
+/// 
+/// **Returns:** `true` if every test passed
+pub fn test_file(filepath: &str) -> bool {
+    test_file_with_context(filepath, &Context::new())
+}
 
-/// A helper function that reduces the code required to print an error
+/// Used when running the tests in a stackathon file with custom settings
 /// 
-/// Gives the helpful arrow to the error
+/// **Arguments:**
+/// * `filepath`: The path to the source file to test
+/// * `context`: The settings every test is run with, eg. limits
+/// 
+/// **Returns:** `true` if every test passed
+pub fn test_file_with_context(filepath: &str, context: &Context) -> bool {
+    let source = match std::fs::read_to_string(filepath) {
+        Ok(s) => s,
+        Err(e) => {
+            print_file_error("E0001", &format!("Error reading file '{}': {}", filepath, e), None, filepath, context);
+            return false;
+        }
+    };
+
+    test_source(source, Some(filepath), context)
+}
+
+/// Used when running the tests in stackathon source code
+/// 
+/// Every named function whose name starts with `test_` is a test.
+/// Each test is run against its own empty stack, and the top level code is not run.
+/// 
+/// Calls aren't nested on the native stack, so recursing too deeply fails the test with an error
+/// instead of crashing, even on a thread with a small stack:
+/// ```
+/// use stackathon::test_string;
+/// 
+/// let code = "@f { dup 0 = { 1 - f $ 1 + } { } gate } @test_f { 990 f $ 990 = assert }";
+/// let deep = "@f { dup 0 = { 1 - f $ 1 + } { } gate } @test_f { 1500 f $ drop }";
+/// let results = std::thread::spawn(|| (test_string(code.to_string()), test_string(deep.to_string())))
+///     .join()
+///     .unwrap();
+/// //Expected Output: Runtime Error[E0206](1:22): Stack overflow, calls were nested deeper than 1000.
+/// assert_eq!(results, (true, false));
+/// ```
+/// 
+/// **Arguments:**
+/// * `source`: The stackathon source code to test
+/// 
+/// **Returns:** `true` if every test passed
+pub fn test_string(source: String) -> bool {
+    test_source(source, None, &Context::new())
+}
+
+/// Runs the tests in stackathon code, printing the results
+/// 
+/// **Arguments:**
+/// * `source`: The stackathon source code to test
+/// * `file`: The path the source came from, for errors
+/// * `context`: The settings every test is run with
+/// 
+/// **Returns:** `true` if every test passed
+fn test_source(source: String, file: Option<&str>, context: &Context) -> bool {
+    let mut functions: HashMap<String, Vec<Token>> = HashMap::new();
+
+    if let Err(errors) = tokenize(&source, &mut functions, &context.permissions, &LibrarySearch::new(file, &context.lib_paths)) {
+        print_errors(&source, file, &errors, context);
+        return false;
+    }
+
+    //Tags have no body, so they can't be tests
+    let mut tests: Vec<&String> = functions.iter()
+        .filter(|(name, body)| name.starts_with("test_") && !body.is_empty())
+        .map(|(name, _)| name)
+        .collect();
+    tests.sort();
+
+    let total = tests.len();
+    println!("running {} tests", total);
+
+    let mut failed = Vec::new();
+    for name in tests {
+        let mut stack = vm::Stack::new();
+        let mut context = context.clone();
+        context.reset();
+        match execute(&functions[name], &mut stack, &functions, &mut context) {
+            Ok(()) => println!("test {} ... ok", name),
+            Err(e) => {
+                println!("test {} ... FAILED", name);
+                failed.push((name, e));
+            }
+        }
+    }
+
+    for (name, error) in &failed {
+        if context.error_format == ErrorFormat::Text {
+            eprintln!("\n---- {} ----", name);
+        }
+        Diagnostic::from(error).print(&source, file, context);
+    }
+
+    println!("\ntest result: {}. {} passed; {} failed", if failed.is_empty() {"ok"} else {"FAILED"}, total - failed.len(), failed.len());
+
+    failed.is_empty()
+}
+
+
+/// Used when checking a stackathon file for mistakes without running it
 /// 
 /// **Arguments:**
-/// * `source`: The source file from which the error came from
-/// * `error_description`: The error's description, printed
-/// * `error_row`: The position row where the error happened
-/// * `error_col`: The position column where the error happened
-fn print_error(source: &str, error_description: &str, error_row: usize, error_col: usize) {
-    eprintln!("{}", error_description);
-    eprintln!("{}", source.lines().nth(error_row - 1).unwrap());
-    eprintln!("{}\x1b[38;5;196m^\x1b[0m", " ".repeat(error_col - 1));
-    //if it seems complicated: print col - 1 spaces. Then switch color to red, print '^' then reset color
-    return;
-}
\ No newline at end of file
+/// * `filepath`: The path to the source file to check
+/// 
+/// **Returns:** `true` if no errors were found, warnings are allowed
+pub fn check_file(filepath: &str) -> bool {
+    check_file_with_context(filepath, &Context::new())
+}
+
+/// Used when checking a stackathon file for mistakes with custom settings
+/// 
+/// **Arguments:**
+/// * `filepath`: The path to the source file to check
+/// * `context`: The settings to check with, eg. how errors are shown
+/// 
+/// **Returns:** `true` if no errors were found, warnings are allowed
+pub fn check_file_with_context(filepath: &str, context: &Context) -> bool {
+    let source = match std::fs::read_to_string(filepath) {
+        Ok(s) => s,
+        Err(e) => {
+            print_file_error("E0001", &format!("Error reading file '{}': {}", filepath, e), None, filepath, context);
+            return false;
+        }
+    };
+
+    check_source(source, Some(filepath), context)
+}
+
+/// Used when checking stackathon source code for mistakes without running it
+/// 
+/// Prints the stack effect of every function, eg. `@add2 ( int -- int )`, then any stack underflows,
+/// type errors and unbalanced loops that are certain to happen.
+/// ```
+/// use stackathon::check_string;
+/// 
+/// let ok = check_string("@add2 { 2 + } 1 add2 $ print \"a\" true +".to_string());
+/// //Expected Output: @add2 ( number -- number )
+/// //Check error[E0402](1:40): `+` expected two numbers or two strings, found string and bool
+/// assert!(!ok);
+/// ```
+/// 
+/// **Arguments:**
+/// * `source`: The stackathon source code to check
+/// 
+/// **Returns:** `true` if no errors were found, warnings are allowed
+pub fn check_string(source: String) -> bool {
+    check_source(source, None, &Context::new())
+}
+
+/// Checks stackathon code, printing each function's stack effect and any problems
+/// 
+/// **Arguments:**
+/// * `source`: The stackathon source code to check
+/// * `file`: The path the source came from, for errors
+/// * `context`: The settings for how to show the problems
+/// 
+/// **Returns:** `true` if no errors were found
+fn check_source(source: String, file: Option<&str>, context: &Context) -> bool {
+    let mut functions: HashMap<String, Vec<Token>> = HashMap::new();
+
+    let tokens = match tokenize(&source, &mut functions, &context.permissions, &LibrarySearch::new(file, &context.lib_paths)) {
+        Ok(t) => t,
+        Err(errors) => {
+            print_errors(&source, file, &errors, context);
+            return false;
+        }
+    };
+
+    //Functions from libraries are only used for their effects
+    let local: HashSet<String> = functions.keys()
+        .filter(|name| find_definition(&source, name).is_some())
+        .cloned()
+        .collect();
+    let report = check(&tokens, &functions, &local);
+
+    for (name, effect) in &report.effects {
+        //Declared effects are shown as they were written, with their labels
+        match (functions[name].first(), effect) {
+            (Some(Token {kind: TokenType::Signature(signature), ..}), _) => println!("@{} {}", name, signature),
+            (_, Some(effect)) => println!("@{} {}", name, effect),
+            (_, None) => println!("@{} ( unknown )", name),
+        }
+    }
+    for error in &report.errors {
+        Diagnostic::from(error).print(&source, file, context);
+    }
+
+    let warnings = report.errors.iter().filter(|error| error.is_warning()).count();
+    let errors = report.errors.len() - warnings;
+    if context.error_format == ErrorFormat::Text {
+        if report.errors.is_empty() {
+            eprintln!("No problems found.");
+        } else {
+            eprintln!("Found {} error{} and {} warning{}.", errors, if errors == 1 {""} else {"s"}, warnings, if warnings == 1 {""} else {"s"});
+        }
+    }
+    errors == 0
+}
+
+/// Used when looking for common mistakes in a stackathon file
+/// 
+/// **Arguments:**
+/// * `filepath`: The path to the source file to lint
+/// 
+/// **Returns:** `true` if there were no warnings
+pub fn lint_file(filepath: &str) -> bool {
+    lint_file_with_context(filepath, &Lints::all(), &Context::new())
+}
+
+/// Used when looking for common mistakes in a stackathon file with custom settings
+/// 
+/// **Arguments:**
+/// * `filepath`: The path to the source file to lint
+/// * `lints`: Which lints to look for
+/// * `context`: The settings for how to show warnings
+/// 
+/// **Returns:** `true` if there were no warnings
+pub fn lint_file_with_context(filepath: &str, lints: &Lints, context: &Context) -> bool {
+    let source = match std::fs::read_to_string(filepath) {
+        Ok(s) => s,
+        Err(e) => {
+            print_file_error("E0001", &format!("Error reading file '{}': {}", filepath, e), None, filepath, context);
+            return false;
+        }
+    };
+
+    lint_source(source, Some(filepath), lints, context)
+}
+
+/// Used when looking for common mistakes in stackathon source code
+/// 
+/// A comment like `;lint-allow dup-drop;` allows the lints it names on its own line and the line after.
+/// ```
+/// use stackathon::lint_string;
+/// 
+/// let ok = lint_string("5 dup drop print ;lint-allow drop-after-literal; 3 drop".to_string());
+/// //Expected Output: Lint warning[E0503](1:3): `dup drop` does nothing (dup-drop)
+/// assert!(!ok);
+/// ```
+/// 
+/// **Arguments:**
+/// * `source`: The stackathon source code to lint
+/// 
+/// **Returns:** `true` if there were no warnings
+pub fn lint_string(source: String) -> bool {
+    lint_source(source, None, &Lints::all(), &Context::new())
+}
+
+/// Lints stackathon code, printing any warnings
+/// 
+/// **Arguments:**
+/// * `source`: The stackathon source code to lint
+/// * `file`: The path the source came from, for warnings
+/// * `lints`: Which lints to look for
+/// * `context`: The settings for how to show warnings
+/// 
+/// **Returns:** `true` if there were no warnings
+fn lint_source(source: String, file: Option<&str>, lints: &Lints, context: &Context) -> bool {
+    let mut functions: HashMap<String, Vec<Token>> = HashMap::new();
+
+    let tokens = match tokenize(&source, &mut functions, &context.permissions, &LibrarySearch::new(file, &context.lib_paths)) {
+        Ok(t) => t,
+        Err(errors) => {
+            print_errors(&source, file, &errors, context);
+            return false;
+        }
+    };
+
+    let warnings = lint(&source, &tokens, &functions, lints);
+    for warning in &warnings {
+        Diagnostic::from(warning).print(&source, file, context);
+    }
+    if context.error_format == ErrorFormat::Text {
+        match warnings.len() {
+            0 => eprintln!("No problems found."),
+            1 => eprintln!("Found 1 warning."),
+            count => eprintln!("Found {} warnings.", count),
+        }
+    }
+    warnings.is_empty()
+}
+
+/// Used when formatting a stackathon file in place
+/// 
+/// **Arguments:**
+/// * `filepath`: The path to the source file to format
+/// 
+/// **Returns:** `true` if the file could be formatted
+pub fn format_file(filepath: &str) -> bool {
+    format_file_with_context(filepath, false, &Context::new())
+}
+
+/// Used when formatting a stackathon file with custom settings
+/// 
+/// **Arguments:**
+/// * `filepath`: The path to the source file to format
+/// * `check`: Only checks that the file is formatted, without changing it
+/// * `context`: The settings for how to show errors
+/// 
+/// **Returns:** `true` if the file was formatted, or in check mode if it already was
+pub fn format_file_with_context(filepath: &str, check: bool, context: &Context) -> bool {
+    let source = match std::fs::read_to_string(filepath) {
+        Ok(s) => s,
+        Err(e) => {
+            print_file_error("E0001", &format!("Error reading file '{}': {}", filepath, e), None, filepath, context);
+            return false;
+        }
+    };
+
+    let formatted = match format(&source) {
+        Ok(formatted) => formatted,
+        Err(errors) => {
+            print_errors(&source, Some(filepath), &errors, context);
+            return false;
+        }
+    };
+    if formatted == source {
+        return true;
+    }
+    if check {
+        let line = source.lines().zip(formatted.lines())
+            .position(|(old, new)| old != new)
+            .unwrap_or_else(|| source.lines().count().min(formatted.lines().count()));
+        println!("{} is not formatted, starting at line {}", filepath, line + 1);
+        return false;
+    }
+    if let Err(e) = std::fs::write(filepath, formatted) {
+        eprintln!("Error writing file '{}': {}", filepath, e);
+        return false;
+    }
+    true
+}
+
+/// Used when formatting stackathon source code
+/// 
+/// Blocks and definitions are indented by four spaces, and tokens on the same line are
+/// separated by one space. Line breaks and comments are kept.
+/// ```
+/// use stackathon::format_string;
+/// 
+/// let formatted = format_string("@add2 {\n2    + ;adds two;\n\n\n}\n5 add2 $".to_string());
+/// assert_eq!(formatted.unwrap(), "@add2 {\n    2 + ;adds two;\n}\n5 add2 $\n");
+/// 
+/// //Formatting again changes nothing, even after a comment that runs to the end of the file
+/// let once = format_string("1 print ;note\n".to_string()).unwrap();
+/// assert_eq!(format_string(once.clone()).unwrap(), once);
+/// ```
+/// 
+/// **Arguments:**
+/// * `source`: The stackathon source code to format
+/// 
+/// **Returns:** The formatted code, or `None` if it has errors, which are printed
+pub fn format_string(source: String) -> Option<String> {
+    match format(&source) {
+        Ok(formatted) => Some(formatted),
+        Err(errors) => {
+            print_errors(&source, None, &errors, &Context::new());
+            None
+        }
+    }
+}
+
+/// Used when writing a reference page for a stackathon file or library
+/// 
+/// **Arguments:**
+/// * `filepath`: The path to the source file, or to a `.stk.lib` library
+/// * `format`: Whether to write Markdown or HTML
+/// 
+/// **Returns:** `true` if the page was written to stdout
+pub fn doc_file(filepath: &str, format: DocFormat) -> bool {
+    doc_file_with_context(filepath, format, &Context::new())
+}
+
+/// Used when writing a reference page for a stackathon file or library with custom settings
+/// 
+/// **Arguments:**
+/// * `filepath`: The path to the source file, or to a `.stk.lib` library
+/// * `format`: Whether to write Markdown or HTML
+/// * `context`: The settings for loading libraries and showing errors
+/// 
+/// **Returns:** `true` if the page was written to stdout
+pub fn doc_file_with_context(filepath: &str, format: DocFormat, context: &Context) -> bool {
+    let filename = filepath.rsplit(['/', '\\']).next().unwrap_or(filepath);
+    if let Some(title) = filename.strip_suffix(".stk.lib") {
+        let content = match std::fs::read(filepath) {
+            Ok(content) => content,
+            Err(e) => {
+                print_file_error("E0001", &format!("Error reading file '{}': {}", filepath, e), None, filepath, context);
+                return false;
+            }
+        };
+        let (functions, docs) = match read_library(&content) {
+            Ok(library) => library,
+            Err(e) => {
+                print_file_error("E0108", &format!("Error reading library '{}'", filepath), Some((e.code(), e.message())), filepath, context);
+                return false;
+            }
+        };
+        //A library doesn't keep the order things were defined in
+        let mut names: Vec<String> = functions.keys().cloned().collect();
+        names.sort();
+        print!("{}", render(title, &entries(&names, &functions, &docs), format));
+        return true;
+    }
+
+    let source = match std::fs::read_to_string(filepath) {
+        Ok(s) => s,
+        Err(e) => {
+            print_file_error("E0001", &format!("Error reading file '{}': {}", filepath, e), None, filepath, context);
+            return false;
+        }
+    };
+    let title = filename.strip_suffix(".stk").unwrap_or(filename);
+    match doc_source(&source, title, Some(filepath), format, context) {
+        Some(page) => {
+            print!("{}", page);
+            true
+        },
+        None => false,
+    }
+}
+
+/// Used when writing a reference page for stackathon source code
+/// 
+/// A doc comment starts with `;;;` and goes straight above the function or tag it is about.
+/// Functions are listed with their declared stack effect, or the one the checker works out.
+/// ```
+/// use stackathon::{DocFormat, doc_string};
+/// 
+/// let page = doc_string(";;; Adds two to a number ;\n@add2 { 2 + }".to_string(), DocFormat::Markdown);
+/// assert_eq!(page.unwrap(), "# Reference\n\n## `add2`\n`( number -- number )`\n\nAdds two to a number\n");
+/// ```
+/// 
+/// **Arguments:**
+/// * `source`: The stackathon source code to write a page for
+/// * `format`: Whether to write Markdown or HTML
+/// 
+/// **Returns:** The page, or `None` if the code has errors, which are printed
+pub fn doc_string(source: String, format: DocFormat) -> Option<String> {
+    doc_source(&source, "Reference", None, format, &Context::new())
+}
+
+/// Writes a reference page for the functions and tags defined in stackathon code, in the order they are defined
+/// 
+/// **Arguments:**
+/// * `source`: The stackathon source code to write a page for
+/// * `title`: What the page is about
+/// * `file`: The path the source came from, for errors
+/// * `format`: Whether to write Markdown or HTML
+/// * `context`: The settings for loading libraries and showing errors
+/// 
+/// **Returns:** The page, or `None` if the code has errors
+fn doc_source(source: &str, title: &str, file: Option<&str>, format: DocFormat, context: &Context) -> Option<String> {
+    let mut functions: HashMap<String, Vec<Token>> = HashMap::new();
+
+    if let Err(errors) = tokenize(source, &mut functions, &context.permissions, &LibrarySearch::new(file, &context.lib_paths)) {
+        print_errors(source, file, &errors, context);
+        return None;
+    }
+
+    //Functions from libraries are documented with the library
+    let mut local: Vec<(String, TokenPosition)> = functions.keys()
+        .filter_map(|name| Some((name.clone(), find_definition(source, name)?)))
+        .collect();
+    local.sort_by_key(|(_, pos)| (pos.row, pos.col));
+    let names: Vec<String> = local.into_iter().map(|(name, _)| name).collect();
+    Some(render(title, &entries(&names, &functions, &find_docs(source)), format))
+}
+
+/// Used when running a language server for editors over stdin and stdout
+/// 
+/// **Returns:** `true` if the editor shut the server down before it exited
+pub fn language_server() -> bool {
+    language_server_with_context(&Context::new())
+}
+
+/// Used when running a language server with custom permissions
+/// 
+/// **Arguments:**
+/// * `context`: The permissions used when loading libraries
+/// 
+/// **Returns:** `true` if the editor shut the server down before it exited
+pub fn language_server_with_context(context: &Context) -> bool {
+    language_server_with_io(std::io::stdin().lock(), std::io::stdout().lock(), context)
+}
+
+/// Used when running a language server over something other than stdin and stdout, eg. to script an editor
+/// ```
+/// use stackathon::{Context, language_server_with_io};
+/// 
+/// let message = |body: &str| format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+/// let text = r#"@Red\n@sq { dup * }\nRed print"#;
+/// let input = [
+///     message(&format!(r#"{{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{{"textDocument":{{"uri":"file:///t.stk","text":"{}"}}}}}}"#, text)),
+///     message(r#"{"jsonrpc":"2.0","id":1,"method":"textDocument/hover","params":{"textDocument":{"uri":"file:///t.stk"},"position":{"line":2,"character":1}}}"#),
+///     message(r#"{"jsonrpc":"2.0","method":"exit"}"#),
+/// ].concat();
+/// 
+/// let mut output = Vec::new();
+/// language_server_with_io(input.as_bytes(), &mut output, &Context::new());
+/// let output = String::from_utf8(output).unwrap();
+/// //A tag has no body, so only its name is shown
+/// assert!(output.contains(r#""value":"```stackathon\n@Red\n```"#));
+/// ```
+/// 
+/// **Arguments:**
+/// * `input`: Where messages from the editor are read from
+/// * `output`: Where responses and notifications are written
+/// * `context`: The permissions used when loading libraries
+/// 
+/// **Returns:** `true` if the editor shut the server down before it exited
+pub fn language_server_with_io(input: impl BufRead, output: impl Write, context: &Context) -> bool {
+    lsp::serve(input, output, context)
+}
+
+/// Prints every error from tokenizing, in source order, then how many there were
+/// 
+/// **Arguments:**
+/// * `source`: The source file from which the errors came from
+/// * `file`: The path of the source file, if it came from one
+/// * `errors`: The errors, already in source order
+/// * `context`: The settings for how to show the errors
+fn print_errors(source: &str, file: Option<&str>, errors: &[TokenizerError], context: &Context) {
+    for error in errors {
+        Diagnostic::from(error).print(source, file, context);
+    }
+    //Every line of JSON output is an error, so there is no count
+    if context.error_format == ErrorFormat::Json {
+        return;
+    }
+    if errors.len() == 1 {
+        eprintln!("Found 1 error.");
+    } else {
+        eprintln!("Found {} errors.", errors.len());
+    }
+}
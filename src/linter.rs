@@ -0,0 +1,256 @@
+use std::{collections::{HashMap, HashSet}, fmt::Display};
+
+use crate::{lexer::{RawKind, Token, TokenPosition, TokenType, find_definition, scan}, types::{Keyword, Operation, Value}};
+
+/// A mistake the linter looks for
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lint {
+    /// A function that nothing calls
+    UnusedFunction,
+    /// A value that is pushed and then dropped straight away
+    DropAfterLiteral,
+    /// `dup drop`, which does nothing
+    DupDrop,
+    /// A tag that is never compared with `=` or `!=`
+    UncomparedTag,
+    /// A `gate` whose condition is always `true` or always `false`
+    ConstantCondition,
+    /// A `loop` whose body always leaves `true`, so it never ends
+    EndlessLoop,
+}
+
+impl Lint {
+    /// Every lint, in the order they are listed
+    pub const ALL: [Lint; 6] = [
+        Lint::UnusedFunction,
+        Lint::DropAfterLiteral,
+        Lint::DupDrop,
+        Lint::UncomparedTag,
+        Lint::ConstantCondition,
+        Lint::EndlessLoop,
+    ];
+
+    /// The name used to enable, disable and allow the lint, eg. "unused-function"
+    pub fn name(self) -> &'static str {
+        match self {
+            Lint::UnusedFunction => "unused-function",
+            Lint::DropAfterLiteral => "drop-after-literal",
+            Lint::DupDrop => "dup-drop",
+            Lint::UncomparedTag => "uncompared-tag",
+            Lint::ConstantCondition => "constant-condition",
+            Lint::EndlessLoop => "endless-loop",
+        }
+    }
+
+    /// Finds a lint by its name
+    pub fn from_name(name: &str) -> Option<Lint> {
+        Lint::ALL.into_iter().find(|lint| lint.name() == name)
+    }
+
+    /// The stable code for this lint, listed in the README
+    pub fn code(self) -> &'static str {
+        match self {
+            Lint::UnusedFunction => "E0501",
+            Lint::DropAfterLiteral => "E0502",
+            Lint::DupDrop => "E0503",
+            Lint::UncomparedTag => "E0504",
+            Lint::ConstantCondition => "E0505",
+            Lint::EndlessLoop => "E0507",
+        }
+    }
+}
+
+impl Display for Lint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// The lints that are looked for
+///
+/// Every lint is enabled by default.
+#[derive(Debug, Clone)]
+pub struct Lints {
+    enabled: HashSet<Lint>,
+}
+
+impl Lints {
+    /// Enables every lint
+    pub fn all() -> Self {
+        Lints {enabled: HashSet::from(Lint::ALL)}
+    }
+
+    /// Enables no lints
+    pub fn none() -> Self {
+        Lints {enabled: HashSet::new()}
+    }
+
+    pub fn enable(&mut self, lint: Lint) {
+        self.enabled.insert(lint);
+    }
+
+    pub fn disable(&mut self, lint: Lint) {
+        self.enabled.remove(&lint);
+    }
+
+    pub fn is_enabled(&self, lint: Lint) -> bool {
+        self.enabled.contains(&lint)
+    }
+}
+
+impl Default for Lints {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+/// A mistake found by the linter
+#[derive(Debug)]
+pub struct LintWarning {
+    pub lint: Lint,
+    pub pos: TokenPosition,
+    pub message: String,
+    /// Another place in the code that helps explain the warning
+    pub note: Option<(String, TokenPosition)>,
+}
+
+impl Display for LintWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Lint warning[{}]({}:{}): {} ({})", self.lint.code(), self.pos.row, self.pos.col, self.message, self.lint)
+    }
+}
+
+/// Where a function or tag is used
+struct Reference {
+    /// The function whose body uses it, or `None` for the top level
+    from: Option<String>,
+    /// Whether it is straight away compared with `=` or `!=`
+    compared: bool,
+}
+
+/// Looks for common mistakes in tokenized code
+///
+/// A comment like `;lint-allow dup-drop;` allows the lints it names on its own line and the line after.
+///
+/// **Arguments:**
+/// * `source`: The source code, for definitions, `use` and comments
+/// * `tokens`: The top level code
+/// * `functions`: The function table, including library functions
+/// * `lints`: Which lints to look for
+///
+/// **Returns:** The warnings, in source order
+pub fn lint(source: &str, tokens: &[Token], functions: &HashMap<String, Vec<Token>>, lints: &Lints) -> Vec<LintWarning> {
+    let mut warnings = Vec::new();
+    //Functions replaced by a library have the library's body, so they aren't looked at
+    let mut local: Vec<(&String, TokenPosition)> = functions.keys()
+        .filter_map(|name| find_definition(source, name).map(|pos| (name, pos)))
+        .collect();
+    local.sort_by_key(|(_, pos)| (pos.row, pos.col));
+
+    let mut references: HashMap<String, Vec<Reference>> = HashMap::new();
+    lint_code(tokens, None, functions, &mut references, &mut warnings);
+    for (name, _) in &local {
+        lint_code(&functions[*name], Some(name), functions, &mut references, &mut warnings);
+    }
+
+    //A file with no top level code is a library, so its functions are used elsewhere
+    let library = tokens.is_empty();
+    for (name, pos) in &local {
+        let body = &functions[*name];
+        let uses = references.get(*name).map_or(&[][..], |uses| uses.as_slice());
+        if body.is_empty() {
+            if uses.is_empty() && !library {
+                warnings.push(LintWarning {lint: Lint::UncomparedTag, pos: *pos, message: format!("Tag '{}' is never used", name), note: None});
+            } else if !uses.is_empty() && !uses.iter().any(|reference| reference.compared) {
+                warnings.push(LintWarning {lint: Lint::UncomparedTag, pos: *pos, message: format!("Tag '{}' is never compared with `=` or `!=`", name), note: None});
+            }
+        } else if !library && !name.starts_with("test_") && !uses.iter().any(|reference| reference.from.as_ref() != Some(*name)) {
+            warnings.push(LintWarning {lint: Lint::UnusedFunction, pos: *pos, message: format!("Function '{}' is never used", name), note: None});
+        }
+    }
+
+    let allowed = allowed_lints(source);
+    warnings.retain(|warning| lints.is_enabled(warning.lint)
+        && !allowed.get(&warning.pos.row).is_some_and(|lints| lints.contains(&warning.lint)));
+    warnings.sort_by_key(|warning| (warning.pos.row, warning.pos.col));
+    warnings
+}
+
+/// Looks for mistakes in a run of tokens and the blocks in it, and collects the functions it uses
+///
+/// **Arguments:**
+/// * `tokens`: The code to look through
+/// * `from`: The function the code is in, or `None` for the top level
+/// * `functions`: The function table
+/// * `references`: Where each use of a function or tag is added
+/// * `warnings`: Where to put any warnings found
+fn lint_code(tokens: &[Token], from: Option<&String>, functions: &HashMap<String, Vec<Token>>, references: &mut HashMap<String, Vec<Reference>>, warnings: &mut Vec<LintWarning>) {
+    for (i, token) in tokens.iter().enumerate() {
+        let next = tokens.get(i + 1).map(|token| &token.kind);
+        match &token.kind {
+            TokenType::Literal(value) => {
+                if let Value::Function(name) = value {
+                    let compared = matches!(next, Some(TokenType::Op(Operation::Equal | Operation::NotEqual)));
+                    references.entry(name.clone()).or_default().push(Reference {from: from.cloned(), compared});
+                }
+                if let Value::Block(block) = value {
+                    lint_code(block, from, functions, references, warnings);
+                }
+                if let Some(TokenType::Keyword(Keyword::DROP)) = next {
+                    let type_name = match value {
+                        Value::Function(name) if functions.get(name).is_some_and(|body| body.is_empty()) => "tag",
+                        value => value.type_name(),
+                    };
+                    warnings.push(LintWarning {lint: Lint::DropAfterLiteral, pos: token.pos, message: format!("This {} is pushed and then dropped straight away", type_name), note: None});
+                }
+            },
+            TokenType::Keyword(Keyword::DUPLICATE) => if let Some(TokenType::Keyword(Keyword::DROP)) = next {
+                warnings.push(LintWarning {lint: Lint::DupDrop, pos: token.pos, message: "`dup drop` does nothing".to_string(), note: None});
+            },
+            TokenType::Keyword(Keyword::GATE) => {
+                //The condition is below the true branch, and the false branch if there is one
+                let runnable = |j: usize| matches!(tokens[j].kind, TokenType::Literal(Value::Block(_) | Value::Function(_)));
+                let condition = match i {
+                    i if i >= 3 && runnable(i - 1) && runnable(i - 2) => Some(&tokens[i - 3]),
+                    i if i >= 2 && runnable(i - 1) => Some(&tokens[i - 2]),
+                    _ => None,
+                };
+                if let Some(Token {kind: TokenType::Literal(Value::Boolean(b)), pos}) = condition {
+                    warnings.push(LintWarning {lint: Lint::ConstantCondition, pos: *pos, message: format!("The `gate` condition is always {}", b), note: None});
+                }
+            },
+            TokenType::Keyword(Keyword::LOOP) if i >= 1 => {
+                let body = match &tokens[i - 1].kind {
+                    TokenType::Literal(Value::Block(block)) => Some(block),
+                    TokenType::Literal(Value::Function(name)) => functions.get(name),
+                    _ => None,
+                };
+                if let Some(Token {kind: TokenType::Literal(Value::Boolean(true)), pos}) = body.and_then(|body| body.last()) {
+                    warnings.push(LintWarning {lint: Lint::EndlessLoop, pos: token.pos, message: "The `loop` body always leaves true, so it never ends".to_string(), note: Some(("this true is left for the condition".to_string(), *pos))});
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Reads `;lint-allow <lint>...;` comments
+///
+/// **Returns:** The lints allowed on each row
+fn allowed_lints(source: &str) -> HashMap<usize, Vec<Lint>> {
+    let mut allowed: HashMap<usize, Vec<Lint>> = HashMap::new();
+    let Ok(tokens) = scan(source) else {
+        return allowed;
+    };
+    for token in tokens.iter().filter(|token| token.kind == RawKind::Comment) {
+        let Some(names) = token.text.trim_matches(';').trim().strip_prefix("lint-allow") else {
+            continue;
+        };
+        let lints: Vec<Lint> = names.split_whitespace().filter_map(Lint::from_name).collect();
+        let last_row = token.pos.row + token.text.matches('\n').count() + 1;
+        for row in token.pos.row..=last_row {
+            allowed.entry(row).or_default().extend(&lints);
+        }
+    }
+    allowed
+}
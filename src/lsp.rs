@@ -0,0 +1,380 @@
+use std::{collections::{HashMap, HashSet}, io::{BufRead, Write}};
+
+use crate::{checker::{check, describe_effect, effects_of}, diagnostic::{Diagnostic, span_length}, formatter::follows_name, json::Json, lexer::{LibrarySearch, RawKind, Token, TokenPosition, TokenType, find_definition, find_docs, find_library_docs, get_keywords, is_hidden, scan, tokenize}, vm::Context};
+
+/// Sent back when a request's method isn't supported
+const METHOD_NOT_FOUND: i32 = -32601;
+/// Sent back when a message isn't valid JSON
+const PARSE_ERROR: i32 = -32700;
+
+/// What kind of thing a completion is, as numbered by the protocol
+const COMPLETION_FUNCTION: usize = 3;
+const COMPLETION_KEYWORD: usize = 14;
+
+/// Severities, as numbered by the protocol
+const SEVERITY_ERROR: usize = 1;
+const SEVERITY_WARNING: usize = 2;
+
+/// A language server, talking to an editor over the Language Server Protocol
+///
+/// Documents are always sent whole, so each one is just kept as text.
+struct Server<'a, W: Write> {
+    output: W,
+    context: &'a Context,
+    /// The text of each open document, by URI
+    documents: HashMap<String, String>,
+    shut_down: bool,
+}
+
+/// Runs a language server until the editor tells it to exit
+///
+/// Messages are JSON-RPC with a `Content-Length` header, as the protocol describes. Diagnostics come
+/// from tokenizing and checking each document whenever it changes.
+///
+/// **Arguments:**
+/// * `input`: Where messages from the editor are read from
+/// * `output`: Where responses and notifications are written
+/// * `context`: The permissions used when loading libraries
+///
+/// **Returns:** `true` if the editor asked the server to shut down before it exited
+pub fn serve(mut input: impl BufRead, output: impl Write, context: &Context) -> bool {
+    let mut server = Server {output, context, documents: HashMap::new(), shut_down: false};
+    while let Some(body) = read_message(&mut input) {
+        let Some(message) = Json::parse(&body) else {
+            server.respond_error(Json::Null, PARSE_ERROR, "Invalid JSON");
+            continue;
+        };
+        let method = message.get("method").and_then(Json::as_str).unwrap_or("");
+        if method == "exit" {
+            return server.shut_down;
+        }
+        let params = message.get("params").unwrap_or(&Json::Null);
+        match message.get("id") {
+            Some(id) => server.request(id.clone(), method, params),
+            None => server.notification(method, params),
+        }
+    }
+    //The editor went away without saying so
+    false
+}
+
+/// Reads the body of the next message, or `None` once there are no more
+fn read_message(input: &mut impl BufRead) -> Option<String> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') && name.eq_ignore_ascii_case("Content-Length") {
+            length = value.trim().parse().ok();
+        }
+    }
+    let mut body = vec![0; length?];
+    input.read_exact(&mut body).ok()?;
+    String::from_utf8(body).ok()
+}
+
+impl<W: Write> Server<'_, W> {
+    fn send(&mut self, message: Json) {
+        let body = message.to_string();
+        //If the editor has gone away there is nobody to tell
+        let _ = write!(self.output, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+        let _ = self.output.flush();
+    }
+
+    fn respond(&mut self, id: Json, result: Json) {
+        self.send(Json::object([("jsonrpc", "2.0".into()), ("id", id), ("result", result)]));
+    }
+
+    fn respond_error(&mut self, id: Json, code: i32, message: &str) {
+        let error = Json::object([("code", Json::Number(code as f64)), ("message", message.into())]);
+        self.send(Json::object([("jsonrpc", "2.0".into()), ("id", id), ("error", error)]));
+    }
+
+    fn request(&mut self, id: Json, method: &str, params: &Json) {
+        let result = match method {
+            "initialize" => Json::object([
+                ("capabilities", Json::object([
+                    //Whole documents are sent on every change
+                    ("textDocumentSync", 1.into()),
+                    ("completionProvider", Json::object([])),
+                    ("definitionProvider", true.into()),
+                    ("hoverProvider", true.into()),
+                ])),
+                ("serverInfo", Json::object([("name", "stackathon".into()), ("version", env!("CARGO_PKG_VERSION").into())])),
+            ]),
+            "shutdown" => {
+                self.shut_down = true;
+                Json::Null
+            },
+            "textDocument/completion" => self.completion(params),
+            "textDocument/definition" => self.definition(params),
+            "textDocument/hover" => self.hover(params),
+            _ => {
+                self.respond_error(id, METHOD_NOT_FOUND, &format!("Unknown method '{}'", method));
+                return;
+            },
+        };
+        self.respond(id, result);
+    }
+
+    fn notification(&mut self, method: &str, params: &Json) {
+        let document = params.get("textDocument");
+        let Some(uri) = document.and_then(|document| document.get("uri")).and_then(Json::as_str) else {
+            return;
+        };
+        let uri = uri.to_string();
+        match method {
+            "textDocument/didOpen" => {
+                let text = document.and_then(|document| document.get("text")).and_then(Json::as_str).unwrap_or("");
+                self.documents.insert(uri.clone(), text.to_string());
+                self.publish_diagnostics(&uri);
+            },
+            "textDocument/didChange" => {
+                //With full syncing the last change holds the whole document
+                let text = match params.get("contentChanges") {
+                    Some(Json::Array(changes)) => changes.last().and_then(|change| change.get("text")).and_then(Json::as_str),
+                    _ => None,
+                };
+                if let Some(text) = text {
+                    self.documents.insert(uri.clone(), text.to_string());
+                    self.publish_diagnostics(&uri);
+                }
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                self.send_diagnostics(&uri, Vec::new());
+            },
+            _ => (),
+        }
+    }
+
+    /// Tokenizes and checks a document, sending every problem found to the editor
+    fn publish_diagnostics(&mut self, uri: &str) {
+        let source = &self.documents[uri];
+        let mut functions = HashMap::new();
+        let diagnostics = match tokenize(source, &mut functions, &self.context.permissions, &libraries_for(uri, self.context)) {
+            Err(errors) => errors.iter().map(|error| to_lsp(uri, source, &Diagnostic::from(error), SEVERITY_ERROR)).collect(),
+            Ok(tokens) => {
+                let local: HashSet<String> = functions.keys()
+                    .filter(|name| find_definition(source, name).is_some())
+                    .cloned()
+                    .collect();
+                check(&tokens, &functions, &local).errors.iter().map(|error| {
+                    let severity = if error.is_warning() {SEVERITY_WARNING} else {SEVERITY_ERROR};
+                    to_lsp(uri, source, &Diagnostic::from(error), severity)
+                }).collect()
+            },
+        };
+        self.send_diagnostics(uri, diagnostics);
+    }
+
+    fn send_diagnostics(&mut self, uri: &str, diagnostics: Vec<Json>) {
+        let params = Json::object([("uri", uri.into()), ("diagnostics", Json::Array(diagnostics))]);
+        self.send(Json::object([("jsonrpc", "2.0".into()), ("method", "textDocument/publishDiagnostics".into()), ("params", params)]));
+    }
+
+    /// Offers every keyword, and every function the document and its libraries define
+    fn completion(&self, params: &Json) -> Json {
+        let mut keywords: Vec<&&str> = get_keywords().keys().collect();
+        keywords.sort();
+        let mut items: Vec<Json> = keywords.into_iter().map(|keyword| Json::object([
+            ("label", (*keyword).into()),
+            ("kind", COMPLETION_KEYWORD.into()),
+        ])).collect();
+        if let Some((uri, source)) = self.document(params) {
+            let functions = functions_in(uri, source, self.context);
+            let effects = effects_of(&functions);
+            let mut names: Vec<&String> = functions.keys().filter(|name| !is_hidden(name)).collect();
+            names.sort();
+            for name in names {
+                let mut item = vec![("label".to_string(), name.as_str().into()), ("kind".to_string(), COMPLETION_FUNCTION.into())];
+                if let Some(effect) = describe_effect(&functions[name], effects.get(name)) {
+                    item.push(("detail".to_string(), effect.into()));
+                }
+                items.push(Json::Object(item));
+            }
+        }
+        Json::Array(items)
+    }
+
+    /// Finds the `@name` that defines the function under the cursor
+    fn definition(&self, params: &Json) -> Json {
+        let Some((uri, source)) = self.document(params) else {
+            return Json::Null;
+        };
+        let Some(name) = word_at(source, params) else {
+            return Json::Null;
+        };
+        match find_definition(source, &name) {
+            Some(pos) => Json::object([("uri", uri.into()), ("range", range(source, pos))]),
+            //Functions from libraries have no source to go to
+            None => Json::Null,
+        }
+    }
+
+    /// Shows the function under the cursor, with its body if it is defined in the document, and its doc comment
+    fn hover(&self, params: &Json) -> Json {
+        let Some((uri, source)) = self.document(params) else {
+            return Json::Null;
+        };
+        let Some(name) = word_at(source, params) else {
+            return Json::Null;
+        };
+        let functions = functions_in(uri, source, self.context);
+        let Some(body) = functions.get(&name) else {
+            return Json::Null;
+        };
+        let effect = describe_effect(body, effects_of(&functions).get(&name));
+        let definition = find_definition(source, &name).and_then(|pos| definition_text(source, pos));
+        let mut value = match (definition, effect) {
+            //A declared effect is already part of the definition
+            (Some(text), Some(effect)) if !matches!(body.first(), Some(Token {kind: TokenType::Signature(_), ..})) => {
+                format!("```stackathon\n{}\n```\nStack effect: `{}`", text, effect)
+            },
+            (Some(text), _) => format!("```stackathon\n{}\n```", text),
+            (None, Some(effect)) => format!("```stackathon\n@{} {}\n```\nDefined in a library", name, effect),
+            (None, None) => format!("```stackathon\n@{}\n```\nDefined in a library", name),
+        };
+        let doc = find_docs(source).remove(&name).or_else(|| find_library_docs(source, &libraries_for(uri, self.context)).remove(&name));
+        if let Some(doc) = doc {
+            value.push_str(&format!("\n\n{}", doc));
+        }
+        Json::object([("contents", Json::object([("kind", "markdown".into()), ("value", value.into())]))])
+    }
+
+    /// The URI and text of the open document a request is about
+    fn document<'b>(&'b self, params: &'b Json) -> Option<(&'b str, &'b str)> {
+        let uri = params.get("textDocument")?.get("uri")?.as_str()?;
+        Some((uri, self.documents.get(uri)?))
+    }
+}
+
+/// Fills a function table from a document, keeping what was found before any error
+fn functions_in(uri: &str, source: &str, context: &Context) -> HashMap<String, Vec<Token>> {
+    let mut functions = HashMap::new();
+    let _ = tokenize(source, &mut functions, &context.permissions, &libraries_for(uri, context));
+    functions
+}
+
+/// Where a document's libraries are looked for, starting next to it if it is a file
+fn libraries_for(uri: &str, context: &Context) -> LibrarySearch {
+    LibrarySearch::new(uri_to_path(uri).as_deref(), &context.lib_paths)
+}
+
+/// The path of a `file://` URI, with escapes like `%20` decoded
+fn uri_to_path(uri: &str) -> Option<String> {
+    let encoded = uri.strip_prefix("file://")?.as_bytes();
+    let mut path = Vec::with_capacity(encoded.len());
+    let mut i = 0;
+    while i < encoded.len() {
+        let escaped = encoded.get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (encoded[i], escaped) {
+            (b'%', Some(byte)) => {
+                path.push(byte);
+                i += 3;
+            },
+            (byte, _) => {
+                path.push(byte);
+                i += 1;
+            },
+        }
+    }
+    String::from_utf8(path).ok()
+}
+
+/// The name of the function under the cursor, without the `@` of a definition
+fn word_at(source: &str, params: &Json) -> Option<String> {
+    let position = params.get("position")?;
+    let line: Vec<char> = source.split('\n').nth(position.get("line")?.as_usize()?)?.chars().collect();
+    //Editors count in UTF-16 code units
+    let target = position.get("character")?.as_usize()?;
+    let mut units = 0;
+    let cursor = line.iter().position(|c| {
+        units += c.len_utf16();
+        units > target
+    }).unwrap_or(line.len());
+
+    let is_word = |c: &char| !c.is_whitespace() && !matches!(c, '{' | '}' | '"' | ';' | '@');
+    let start = line[..cursor].iter().rposition(|c| !is_word(c)).map_or(0, |i| i + 1);
+    let end = line[cursor..].iter().position(|c| !is_word(c)).map_or(line.len(), |i| cursor + i);
+    (start < end).then(|| line[start..end].iter().collect())
+}
+
+/// Turns the token starting at `pos` into a protocol range
+fn range(source: &str, pos: TokenPosition) -> Json {
+    let line = source.split('\n').nth(pos.row - 1).unwrap_or("");
+    let character = |col: usize| line.chars().take(col - 1).map(char::len_utf16).sum::<usize>();
+    let start = Json::object([("line", (pos.row - 1).into()), ("character", character(pos.col).into())]);
+    let end = Json::object([("line", (pos.row - 1).into()), ("character", character(pos.col + span_length(line, pos.col)).into())]);
+    Json::object([("start", start), ("end", end)])
+}
+
+/// Turns a diagnostic into the protocol's form, with its notes as related information
+fn to_lsp(uri: &str, source: &str, diagnostic: &Diagnostic, severity: usize) -> Json {
+    let mut message = diagnostic.message.clone();
+    if let Some((code, cause)) = &diagnostic.cause {
+        message.push_str(&format!("\ncaused by {}: {}", code, cause));
+    }
+    let related = diagnostic.notes.iter().map(|note| Json::object([
+        ("location", Json::object([("uri", uri.into()), ("range", range(source, note.pos))])),
+        ("message", note.message.as_str().into()),
+    ])).collect();
+    Json::object([
+        ("range", range(source, diagnostic.pos)),
+        ("severity", severity.into()),
+        ("code", diagnostic.code.into()),
+        ("source", "stackathon".into()),
+        ("message", message.into()),
+        ("relatedInformation", Json::Array(related)),
+    ])
+}
+
+/// The source of the definition starting at `pos`, from its `@name` to its closing brace, or to its name or stack effect if it has no body
+fn definition_text(source: &str, pos: TokenPosition) -> Option<String> {
+    let tokens = scan(source).ok()?;
+    let start = tokens.iter().position(|token| token.pos == pos)?;
+    let name = &tokens[start];
+    //The lexer only looks for a stack effect and a body straight after the name, like the formatter
+    let mut last = start;
+    if let Some(open) = tokens.get(start + 1) && open.text == "(" && follows_name(source, name, open) {
+        last = start + 1 + tokens[start + 1..].iter().position(|token| token.text == ")")?;
+    }
+    let has_body = tokens.get(last + 1)
+        .is_some_and(|token| token.kind == RawKind::OpenBrace && (last > start || follows_name(source, name, token)));
+    if !has_body {
+        //A tag, or a declaration without a body, is only its name and stack effect
+        let end = TokenPosition {row: tokens[last].pos.row, col: tokens[last].pos.col + tokens[last].text.chars().count()};
+        return Some(source[byte_offset(source, pos)?..byte_offset(source, end)?].to_string());
+    }
+    let mut depth = 0;
+    for token in &tokens[last + 1..] {
+        match token.kind {
+            RawKind::OpenBrace => depth += 1,
+            RawKind::CloseBrace => {
+                depth -= 1;
+                if depth == 0 {
+                    let end = TokenPosition {row: token.pos.row, col: token.pos.col + 1};
+                    return Some(source[byte_offset(source, pos)?..byte_offset(source, end)?].to_string());
+                }
+            },
+            _ => (),
+        }
+    }
+    None
+}
+
+/// Where a row and column are in the source, in bytes
+fn byte_offset(source: &str, pos: TokenPosition) -> Option<usize> {
+    let line_start: usize = source.split('\n').take(pos.row - 1).map(|line| line.len() + 1).sum();
+    let line = source.get(line_start..)?.split('\n').next()?;
+    let within = line.char_indices().nth(pos.col - 1).map_or(line.len(), |(i, _)| i);
+    Some(line_start + within)
+}
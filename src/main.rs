@@ -1,24 +1,270 @@
-use std::env;
+use std::{cell::RefCell, env, fs::File, io::{LineWriter, Write}, rc::Rc};
 
-use stackathon::{compile_file, run_file};
+use stackathon::{ColorChoice, Context, Coverage, DocFormat, ErrorFormat, Lint, Lints, Profiler, TraceLog, check_file_with_context, compile_file_with_context, debug_file_with_context, doc_file_with_context, format_file_with_context, language_server_with_context, lint_file_with_context, run_file_with_context, test_file_with_context};
 
 
 
 
+/// What is measured while the program runs, and reported once it ends
+enum Measure<'a> {
+    /// With where to write the folded stacks, if anywhere
+    Profile(Rc<RefCell<Profiler>>, Option<&'a str>),
+    /// With where to write the report
+    Coverage(Rc<RefCell<Coverage>>, &'a str),
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
     if args.len() < 2 {
-        eprintln!("Usage: stackathon <file-path>");
+        print_usage();
+        std::process::exit(1);
+    }
+    let command = args[1].as_str();
+    if command == "lsp" {
+        let mut context = Context::new();
+        for option in &args[2..] {
+            let Some(path) = option.strip_prefix("--lib-path=") else {
+                eprintln!("Unknown argument '{}'", option);
+                std::process::exit(1);
+            };
+            context.lib_paths.push(path.into());
+        }
+        //The exit code tells the editor whether it shut the server down properly
+        if !language_server_with_context(&context) {
+            std::process::exit(1);
+        }
+        return;
+    }
+    let testing = command == "test";
+    let checking = command == "check";
+    let formatting = command == "fmt";
+    let linting = command == "lint";
+    let debugging = command == "debug";
+    let documenting = command == "doc";
+    let rest = if testing || checking || formatting || linting || debugging || documenting {&args[2..]} else {&args[1..]};
+    let Some(filepath) = rest.first() else {
+        print_usage();
+        std::process::exit(1);
+    };
+    //Everything after '--' is given to the script
+    let (options, script_args) = match rest[1..].iter().position(|arg| arg == "--") {
+        Some(i) => (&rest[1..1 + i], &rest[2 + i..]),
+        None => (&rest[1..], &rest[rest.len()..]),
+    };
+    let mut compile = false;
+    let mut check_format = false;
+    let mut lints = Lints::all();
+    let mut only_enabled = false;
+    //Where the trace goes, `None` for stderr
+    let mut trace: Option<Option<&str>> = None;
+    let mut traced_functions = Vec::new();
+    let mut profile = false;
+    let mut folded_path = None;
+    let mut coverage_path = None;
+    let mut doc_format = DocFormat::Markdown;
+    let running = !checking && !formatting && !linting && !debugging && !documenting;
+    let mut context = Context::new();
+    for option in options {
+        if let Some(format) = option.strip_prefix("--error-format=") {
+            context.error_format = match format {
+                "text" => ErrorFormat::Text,
+                "json" => ErrorFormat::Json,
+                _ => {
+                    eprintln!("Unknown error format '{}', expected 'text' or 'json'", format);
+                    std::process::exit(1);
+                }
+            };
+            continue;
+        }
+        if let Some(color) = option.strip_prefix("--color=") {
+            context.color = match color {
+                "auto" => ColorChoice::Auto,
+                "always" => ColorChoice::Always,
+                "never" => ColorChoice::Never,
+                _ => {
+                    eprintln!("Unknown color choice '{}', expected 'auto', 'always' or 'never'", color);
+                    std::process::exit(1);
+                }
+            };
+            continue;
+        }
+        if linting && let Some((setting, names)) = option.split_once('=') && (setting == "--enable" || setting == "--disable") {
+            //Enabling lints only looks for those ones
+            if setting == "--enable" && !only_enabled {
+                lints = Lints::none();
+                only_enabled = true;
+            }
+            for name in names.split(',') {
+                let Some(lint) = Lint::from_name(name) else {
+                    let names: Vec<&str> = Lint::ALL.iter().map(|lint| lint.name()).collect();
+                    eprintln!("Unknown lint '{}', expected one of: {}", name, names.join(", "));
+                    std::process::exit(1);
+                };
+                if setting == "--enable" {
+                    lints.enable(lint);
+                } else {
+                    lints.disable(lint);
+                }
+            }
+            continue;
+        }
+        if !formatting && let Some(path) = option.strip_prefix("--lib-path=") {
+            context.lib_paths.push(path.into());
+            continue;
+        }
+        if documenting && let Some(format) = option.strip_prefix("--format=") {
+            doc_format = match format {
+                "markdown" => DocFormat::Markdown,
+                "html" => DocFormat::Html,
+                _ => {
+                    eprintln!("Unknown doc format '{}', expected 'markdown' or 'html'", format);
+                    std::process::exit(1);
+                }
+            };
+            continue;
+        }
+        if running && let Some(path) = option.strip_prefix("--trace=") {
+            trace = Some(Some(path));
+            continue;
+        }
+        if running && let Some(path) = option.strip_prefix("--profile-folded=") {
+            profile = true;
+            folded_path = Some(path);
+            continue;
+        }
+        if running && let Some(path) = option.strip_prefix("--coverage=") {
+            coverage_path = Some(path);
+            continue;
+        }
+        if running && let Some(names) = option.strip_prefix("--trace-function=") {
+            traced_functions.extend(names.split(','));
+            continue;
+        }
+        match option.as_str() {
+            "--trace" if running => trace = Some(None),
+            "--profile" if running => profile = true,
+            "--coverage" if running => coverage_path = Some("lcov.info"),
+            "--lib" if running && !testing => compile = true,
+            "--debug" if !checking && !formatting && !linting && !documenting => context.debug = true,
+            "--check" if formatting => check_format = true,
+            _ => {
+                eprintln!("Unknown argument '{}'", option);
+                std::process::exit(1);
+            }
+        }
+    }
+    context.args = script_args.to_vec();
+    if [profile, trace.is_some(), coverage_path.is_some()].iter().filter(|used| **used).count() > 1 {
+        eprintln!("Only one of '--trace', '--profile' and '--coverage' can be used at once");
+        std::process::exit(1);
+    }
+    let measure = if profile {
+        let profiler = Rc::new(RefCell::new(Profiler::new()));
+        context.tracer = Some(profiler.clone());
+        Some(Measure::Profile(profiler, folded_path))
+    } else if let Some(path) = coverage_path {
+        let coverage = Rc::new(RefCell::new(Coverage::new()));
+        context.tracer = Some(coverage.clone());
+        Some(Measure::Coverage(coverage, path))
+    } else {
+        None
+    };
+    if let Some(path) = trace {
+        let output: Box<dyn Write> = match path {
+            //Each line is written as it is logged, so the trace is kept even if the program exits early
+            Some(path) => match File::create(path) {
+                Ok(file) => Box::new(LineWriter::new(file)),
+                Err(e) => {
+                    eprintln!("Error creating trace file '{}': {}", path, e);
+                    std::process::exit(1);
+                }
+            },
+            None => Box::new(std::io::stderr()),
+        };
+        let log = traced_functions.iter().fold(TraceLog::new(output), |log, name| log.only_in(name));
+        context.tracer = Some(Rc::new(RefCell::new(log)));
+    } else if !traced_functions.is_empty() {
+        eprintln!("'--trace-function' needs '--trace' too");
         std::process::exit(1);
     }
-    let filepath = &args[1];
-    if args.len() > 2 {
-        if args[2] != "--lib" {
-            eprintln!("Unknown argument '{}'", args[2]);
+    if testing {
+        let passed = test_file_with_context(filepath, &context);
+        report(measure, filepath, &context);
+        if !passed {
+            std::process::exit(1);
+        }
+    } else if checking {
+        if !check_file_with_context(filepath, &context) {
+            std::process::exit(1);
+        }
+    } else if linting {
+        if !lint_file_with_context(filepath, &lints, &context) {
             std::process::exit(1);
         }
-        compile_file(filepath);
+    } else if formatting {
+        if !format_file_with_context(filepath, check_format, &context) {
+            std::process::exit(1);
+        }
+    } else if debugging {
+        debug_file_with_context(filepath, &mut context);
+    } else if documenting {
+        if !doc_file_with_context(filepath, doc_format, &context) {
+            std::process::exit(1);
+        }
+    } else if compile {
+        compile_file_with_context(filepath, &context);
     } else {
-        run_file(&filepath);
+        run_file_with_context(filepath, &mut context);
+        report(measure, filepath, &context);
     }
 }
+
+/// Shows or writes what was measured while the program ran
+fn report(measure: Option<Measure>, filepath: &str, context: &Context) {
+    match measure {
+        Some(Measure::Profile(profiler, folded_path)) => {
+            let profiler = profiler.borrow();
+            //The program's own output comes first
+            let _ = std::io::stdout().flush();
+            eprintln!();
+            eprint!("{}", profiler.report());
+            if let Some(path) = folded_path && let Err(e) = std::fs::write(path, profiler.folded()) {
+                eprintln!("Error writing folded stacks to '{}': {}", path, e);
+            }
+        },
+        Some(Measure::Coverage(coverage, path)) => {
+            //The file was already read and tokenized to run it, so this only fails if it changed since
+            let Some(lcov) = std::fs::read_to_string(filepath).ok()
+                .and_then(|source| coverage.borrow().lcov(filepath, &source, context)) else {
+                eprintln!("Error reading '{}' again for the coverage report", filepath);
+                return;
+            };
+            if let Err(e) = std::fs::write(path, lcov) {
+                eprintln!("Error writing coverage to '{}': {}", path, e);
+            }
+        },
+        None => (),
+    }
+}
+
+fn print_usage() {
+    eprintln!("Usage: stackathon <file-path> [options] [-- <script-args>...]");
+    eprintln!("       stackathon <file-path> --lib [options]");
+    eprintln!("       stackathon test <file-path> [options]");
+    eprintln!("       stackathon check <file-path> [options]");
+    eprintln!("       stackathon fmt <file-path> [--check] [options]");
+    eprintln!("       stackathon lint <file-path> [--enable=<lints>] [--disable=<lints>] [options]");
+    eprintln!("       stackathon debug <file-path> [options] [-- <script-args>...]");
+    eprintln!("       stackathon doc <file-path> [--format=<markdown|html>] [options]");
+    eprintln!("       stackathon lsp [--lib-path=<dir>...]");
+    eprintln!("Options:");
+    eprintln!("    --error-format=<text|json>     How errors are shown, json prints one object per line");
+    eprintln!("    --color=<auto|always|never>    When errors are colored, auto checks NO_COLOR and if stderr is a terminal");
+    eprintln!("    --lib-path=<dir>               Also looks for libraries in a directory, after the file's own and STACKATHON_PATH");
+    eprintln!("    --debug                        Checks functions against their declared stack effects when they are called and return");
+    eprintln!("    --trace[=<file>]               Logs every token that runs with the stack before and after it, to stderr or a file");
+    eprintln!("    --trace-function=<names>       Only traces tokens run inside these functions, separated by commas");
+    eprintln!("    --profile                      Prints the calls, tokens and time spent in each function and line when the program ends");
+    eprintln!("    --profile-folded=<file>        Profiles, and writes the time spent in each stack of functions to a file for flamegraph tools");
+    eprintln!("    --coverage[=<file>]            Writes which lines, functions and gate branches ran as an lcov report, to lcov.info by default");
+}
@@ -0,0 +1,257 @@
+use std::{collections::HashMap, fmt::Display, time::{Duration, Instant}};
+
+use crate::{lexer::TokenPosition, vm::{Stack, Tracer}};
+
+/// What the code outside of any function is called in the report
+const TOP_LEVEL: &str = "(top level)";
+
+/// What was measured for a function or line
+#[derive(Debug, Clone, Default)]
+struct Stats {
+    /// How many times a function was called, unused for lines
+    calls: u64,
+    /// How many tokens were run directly in it
+    tokens: u64,
+    /// Time spent in it, including in what it called
+    inclusive: Duration,
+    /// Time spent in it, apart from in named functions it called
+    exclusive: Duration,
+}
+
+/// A function or block that is running
+struct Frame {
+    /// The function's name, or `None` for a block, whose time counts towards the function it is in
+    name: Option<String>,
+    start: Instant,
+    /// The row of the token it is running, or last ran, which time between its tokens counts towards
+    row: usize,
+}
+
+/// A tracer that measures where the time goes, per named function and per source line
+///
+/// Blocks count as part of the function they are run from. Time spent in a function that is
+/// already running further down, by recursion, only counts once towards its inclusive time.
+/// Lines are measured the same way, with each frame being in the line of its last token, so time
+/// between tokens, like starting a call, counts towards the line that made it.
+pub struct Profiler {
+    functions: HashMap<String, Stats>,
+    lines: HashMap<usize, Stats>,
+    /// Exclusive time for each stack of named functions, joined by `;`
+    folded: HashMap<String, Duration>,
+    frames: Vec<Frame>,
+    /// How many frames are in each line, and since when the line has had any
+    active_lines: HashMap<usize, (usize, Instant)>,
+    /// The current stack of named functions, the key into `folded`
+    path: String,
+    /// When the last event happened, which the time since is counted from
+    last: Option<Instant>,
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            functions: HashMap::new(),
+            lines: HashMap::new(),
+            folded: HashMap::new(),
+            frames: Vec::new(),
+            active_lines: HashMap::new(),
+            path: String::new(),
+            last: None,
+        }
+    }
+
+    /// The name that time spent right now counts towards
+    fn current_function(&self) -> &str {
+        self.frames.iter().rev().find_map(|frame| frame.name.as_deref()).unwrap_or(TOP_LEVEL)
+    }
+
+    /// Counts the time since the last event towards the code that was running
+    fn charge(&mut self, now: Instant) {
+        let elapsed = now - *self.last.get_or_insert(now);
+        self.last = Some(now);
+        let Some(frame) = self.frames.last() else {
+            return;
+        };
+        let row = frame.row;
+        let function = self.current_function().to_string();
+        self.functions.entry(function).or_default().exclusive += elapsed;
+        self.lines.entry(row).or_default().exclusive += elapsed;
+        *self.folded.entry(self.path.clone()).or_default() += elapsed;
+    }
+
+    /// Counts another frame as being in a line
+    fn enter_line(&mut self, row: usize, now: Instant) {
+        self.active_lines.entry(row).or_insert((0, now)).0 += 1;
+    }
+
+    /// Counts a frame as no longer being in a line, adding the line's inclusive time once no frame is in it
+    fn leave_line(&mut self, row: usize, now: Instant) {
+        let Some((frames, start)) = self.active_lines.get_mut(&row) else {
+            return;
+        };
+        *frames -= 1;
+        if *frames == 0 {
+            let start = *start;
+            self.active_lines.remove(&row);
+            self.lines.entry(row).or_default().inclusive += now - start;
+        }
+    }
+
+    /// Adds a frame's inclusive time, unless its function is still running further down
+    fn finish(functions: &mut HashMap<String, Stats>, frames: &[Frame], frame: &Frame, end: Instant) {
+        if let Some(name) = &frame.name && !frames.iter().any(|other| other.name.as_ref() == Some(name)) {
+            functions.entry(name.clone()).or_default().inclusive += end - frame.start;
+        }
+    }
+
+    fn update_path(&mut self) {
+        let names: Vec<&str> = self.frames.iter().filter_map(|frame| frame.name.as_deref()).collect();
+        self.path = names.join(";");
+    }
+
+    /// The measurements so far, for functions and then lines, counting frames that are still open
+    /// as ending at the last event, like the top level
+    fn totals(&self) -> (HashMap<String, Stats>, HashMap<usize, Stats>) {
+        let mut functions = self.functions.clone();
+        let mut lines = self.lines.clone();
+        if let Some(end) = self.last {
+            for (i, frame) in self.frames.iter().enumerate() {
+                Self::finish(&mut functions, &self.frames[..i], frame, end);
+            }
+            for (row, (_, start)) in &self.active_lines {
+                lines.entry(*row).or_default().inclusive += end - *start;
+            }
+        }
+        (functions, lines)
+    }
+
+    /// The inclusive and exclusive time of each function that ran, including `(top level)`
+    pub fn function_times(&self) -> HashMap<String, (Duration, Duration)> {
+        self.totals().0.into_iter().map(|(name, stats)| (name, (stats.inclusive, stats.exclusive))).collect()
+    }
+
+    /// The inclusive and exclusive time of each line that ran, by row
+    ///
+    /// Every moment counts towards exactly one line's exclusive time, as it does for functions:
+    /// ```
+    /// use std::{cell::RefCell, rc::Rc, time::Duration};
+    /// use stackathon::{Context, Profiler, run_string_with_context};
+    ///
+    /// let profiler = Rc::new(RefCell::new(Profiler::new()));
+    /// let mut context = Context::new();
+    /// context.tracer = Some(profiler.clone());
+    /// //Recursing by tail calls, and a token that fails
+    /// let code = "@down { 1 - dup 0 > { down $ } gate }\n@work { 2000 down $ drop }\nwork $\n\"a\" 1 +";
+    /// run_string_with_context(code.to_string(), &mut context);
+    ///
+    /// let profiler = profiler.borrow();
+    /// let lines = profiler.line_times();
+    /// assert!(lines.values().all(|(inclusive, exclusive)| exclusive <= inclusive));
+    /// let line_total: Duration = lines.values().map(|(_, exclusive)| *exclusive).sum();
+    /// let function_total: Duration = profiler.function_times().values().map(|(_, exclusive)| *exclusive).sum();
+    /// assert_eq!(line_total, function_total);
+    /// //The recursion is all on the first line
+    /// assert!(lines[&1].1 > lines[&2].1);
+    /// ```
+    pub fn line_times(&self) -> HashMap<usize, (Duration, Duration)> {
+        self.totals().1.into_iter().map(|(row, stats)| (row, (stats.inclusive, stats.exclusive))).collect()
+    }
+
+    /// A table of every function and line that ran, slowest first by exclusive time
+    pub fn report(&self) -> String {
+        let (functions, lines) = self.totals();
+        let mut functions: Vec<(String, Stats)> = functions.into_iter().collect();
+        functions.sort_by(|(a_name, a), (b_name, b)| b.exclusive.cmp(&a.exclusive).then(a_name.cmp(b_name)));
+        let width = functions.iter().map(|(name, _)| name.len()).fold("Function".len(), usize::max);
+
+        let mut report = format!("{:<width$}  {:>8}  {:>8}  {:>12}  {:>12}\n", "Function", "Calls", "Tokens", "Inclusive", "Exclusive");
+        for (name, stats) in &functions {
+            let calls = if name == TOP_LEVEL {"-".to_string()} else {stats.calls.to_string()};
+            report.push_str(&format!("{:<width$}  {:>8}  {:>8}  {:>12}  {:>12}\n",
+                name, calls, stats.tokens, format!("{:.1?}", stats.inclusive), format!("{:.1?}", stats.exclusive)));
+        }
+
+        let mut lines: Vec<(&usize, &Stats)> = lines.iter().collect();
+        lines.sort_by(|(a_row, a), (b_row, b)| b.exclusive.cmp(&a.exclusive).then(a_row.cmp(b_row)));
+        report.push_str(&format!("\n{:<8}  {:>8}  {:>12}  {:>12}\n", "Line", "Tokens", "Inclusive", "Exclusive"));
+        for (row, stats) in lines {
+            report.push_str(&format!("{:<8}  {:>8}  {:>12}  {:>12}\n",
+                row, stats.tokens, format!("{:.1?}", stats.inclusive), format!("{:.1?}", stats.exclusive)));
+        }
+        report
+    }
+
+    /// Each stack of named functions and the microseconds spent in it, one per line, for flamegraph tools
+    pub fn folded(&self) -> String {
+        let mut stacks: Vec<(&String, &Duration)> = self.folded.iter().collect();
+        stacks.sort();
+        stacks.iter()
+            .filter(|(_, time)| time.as_micros() > 0)
+            .map(|(path, time)| format!("{} {}\n", path, time.as_micros()))
+            .collect()
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Tracer for Profiler {
+    fn before(&mut self, _token: &dyn Display, pos: TokenPosition, _stack: &Stack) -> bool {
+        let now = Instant::now();
+        self.charge(now);
+        match self.frames.last_mut() {
+            Some(frame) if frame.row != pos.row => {
+                let row = std::mem::replace(&mut frame.row, pos.row);
+                self.leave_line(row, now);
+                self.enter_line(pos.row, now);
+            },
+            Some(_) => (),
+            None => {
+                self.frames.push(Frame {name: Some(TOP_LEVEL.to_string()), start: now, row: pos.row});
+                self.enter_line(pos.row, now);
+                self.update_path();
+            },
+        }
+        let function = self.current_function().to_string();
+        self.functions.entry(function).or_default().tokens += 1;
+        self.lines.entry(pos.row).or_default().tokens += 1;
+        true
+    }
+
+    fn after(&mut self, _token: &dyn Display, _pos: TokenPosition, _stack: &Stack) {
+        self.charge(Instant::now());
+    }
+
+    fn enter(&mut self, name: Option<&str>, called_at: TokenPosition, tail: bool) {
+        let now = Instant::now();
+        self.charge(now);
+        //A block that ends a function is still part of it
+        if tail && name.is_none() && !self.frames.is_empty() {
+            return;
+        }
+        if tail && let Some(replaced) = self.frames.pop() {
+            Self::finish(&mut self.functions, &self.frames, &replaced, now);
+            self.leave_line(replaced.row, now);
+        }
+        if let Some(name) = name {
+            self.functions.entry(name.to_string()).or_default().calls += 1;
+        }
+        //Until its first token runs, a call is in the line that made it
+        self.frames.push(Frame {name: name.map(str::to_string), start: now, row: called_at.row});
+        self.enter_line(called_at.row, now);
+        self.update_path();
+    }
+
+    fn leave(&mut self) {
+        let now = Instant::now();
+        self.charge(now);
+        if let Some(frame) = self.frames.pop() {
+            Self::finish(&mut self.functions, &self.frames, &frame, now);
+            self.leave_line(frame.row, now);
+        }
+        self.update_path();
+    }
+}
@@ -12,18 +12,36 @@ pub enum SerializationError {
     InvalidVersion,
 }
 
-impl Display for SerializationError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+impl SerializationError {
+    /// The stable code for this error, listed in the README
+    pub fn code(&self) -> &'static str {
+        match self {
+            SerializationError::EndOfFile => "E0301",
+            SerializationError::InvalidTagByte(_) => "E0302",
+            SerializationError::InvalidUTF8Encoding(_) => "E0303",
+            SerializationError::InvalidFile => "E0304",
+            SerializationError::InvalidVersion => "E0305",
+        }
+    }
+
+    /// The error's description, without its kind or code
+    pub fn message(&self) -> String {
         match self {
-            SerializationError::EndOfFile => write!(f,"Serialization Error: Unexpected end of file/data while reading."),
-            SerializationError::InvalidTagByte(t) => write!(f, "Serialization Error: Encountered invalid tag byte: {:#02X}", t),
-            SerializationError::InvalidUTF8Encoding(e) => write!(f, "Serialization Error: Invalid UTF-8 encoding. {}", e),
-            SerializationError::InvalidFile => write!(f, "Serialization Error: Incorrect file for stackathon library."),
-            SerializationError::InvalidVersion => write!(f, "Serialization Error: Incompatible library version."),
+            SerializationError::EndOfFile => "Unexpected end of file/data while reading.".to_string(),
+            SerializationError::InvalidTagByte(t) => format!("Encountered invalid tag byte: {:#02X}", t),
+            SerializationError::InvalidUTF8Encoding(e) => format!("Invalid UTF-8 encoding. {}", e),
+            SerializationError::InvalidFile => "Incorrect file for stackathon library.".to_string(),
+            SerializationError::InvalidVersion => "Incompatible library version.".to_string(),
         }
     }
 }
 
+impl Display for SerializationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Serialization Error[{}]: {}", self.code(), self.message())
+    }
+}
+
 impl Error for SerializationError {}
 
 pub trait ByteSized {
@@ -0,0 +1,82 @@
+use std::{collections::HashSet, fmt::Display, io::Write};
+
+use crate::{lexer::TokenPosition, types::Value, vm::{Stack, Tracer}};
+
+/// A tracer that logs every token that runs, with the stack before and after it
+///
+/// Each token gets one line once it has finished, so a call's line comes after the lines of the
+/// code it ran, which are indented one level deeper:
+/// ```text
+/// 2:5 dup [3] -> [3 3]
+/// ```
+pub struct TraceLog<W: Write> {
+    output: W,
+    /// Only tokens run inside these functions are logged, or every token if it is empty
+    functions: HashSet<String>,
+    /// Whether each function and block that is running is inside one of `functions`, including
+    /// through a tail call from one, so it stays set when the frame is replaced
+    frames: Vec<bool>,
+    /// The stack before each token that hasn't finished yet, innermost last
+    before: Vec<String>,
+}
+
+impl<W: Write> TraceLog<W> {
+    /// Makes a tracer that logs every token
+    ///
+    /// **Arguments:**
+    /// * `output`: Where the log is written, eg. stderr or a file
+    pub fn new(output: W) -> Self {
+        TraceLog {output, functions: HashSet::new(), frames: Vec::new(), before: Vec::new()}
+    }
+
+    /// Only logs the tokens run inside a function, including in what it calls.
+    /// Can be used more than once to log inside any of several functions.
+    pub fn only_in(mut self, function: &str) -> Self {
+        self.functions.insert(function.to_string());
+        self
+    }
+
+    /// Whether tokens running right now are logged
+    fn logging(&self) -> bool {
+        self.functions.is_empty() || self.frames.last().is_some_and(|inside| *inside)
+    }
+}
+
+/// Shows the stack from the bottom up, with the top last
+fn describe_stack(stack: &Stack) -> String {
+    let values: Vec<String> = stack.values().map(Value::describe).collect();
+    format!("[{}]", values.join(" "))
+}
+
+impl<W: Write> Tracer for TraceLog<W> {
+    fn before(&mut self, _token: &dyn Display, _pos: TokenPosition, stack: &Stack) -> bool {
+        self.before.push(if self.logging() {describe_stack(stack)} else {String::new()});
+        true
+    }
+
+    fn after(&mut self, token: &dyn Display, pos: TokenPosition, stack: &Stack) {
+        let before = self.before.pop().unwrap_or_default();
+        if self.logging() {
+            //If the log can't be written, the program still runs
+            let indent = "  ".repeat(self.frames.len());
+            let _ = writeln!(self.output, "{}{}:{} {} {} -> {}", indent, pos.row, pos.col, token, before, describe_stack(stack));
+        }
+    }
+
+    fn enter(&mut self, name: Option<&str>, _called_at: TokenPosition, tail: bool) {
+        let listed = name.is_some_and(|name| self.functions.contains(name));
+        match self.frames.last_mut() {
+            //The function it replaces is still counted, and a block that ends a function is part of it
+            Some(inside) if tail => *inside |= listed,
+            Some(inside) => {
+                let inside = *inside || listed;
+                self.frames.push(inside);
+            },
+            None => self.frames.push(listed),
+        }
+    }
+
+    fn leave(&mut self) {
+        self.frames.pop();
+    }
+}
@@ -1,7 +1,7 @@
 
-use std::{fmt::Display, ops::{Add, Div, Mul, Not, Sub}};
+use std::{collections::HashSet, fmt::Display, ops::{Add, Div, Mul, Not, Sub}};
 
-use crate::{lexer::Token, serial::{ByteSized, SerializationError}};
+use crate::{lexer::{Token, TokenType}, serial::{ByteSized, SerializationError}};
 
 #[derive(Debug)]
 #[derive(Clone)]
@@ -27,6 +27,17 @@ pub enum Keyword {
     USE, //Library invokation
     INPUT, //Gets user input
     STRLEN, //Gets length of string
+    ASSERT, //Fails with an error if the condition is false
+    READ, //File I/O. Reads a whole file into a string
+    READLINES, //Pushes each line of a file, then the number of lines
+    WRITE,
+    APPEND,
+    EXISTS,
+    DELETE,
+    TRY, //Runs a block, and runs a handler if it fails with an I/O error
+    ARGS, //Pushes each command line argument, then the number of them
+    ENV, //Gets an environment variable
+    BREAK, //Pauses in the debugger, and does nothing otherwise
 }
 
 #[derive(Debug)]
@@ -41,6 +52,42 @@ pub enum Value {
     Tag(String), //Is both a manual tag eg. @list or the result of a type eg. 2 type
 }
 
+impl Value {
+    /// Roughly how many bytes this value has allocated, used for memory limits
+    pub fn heap_size(&self) -> usize {
+        match self {
+            Value::String(s) | Value::Function(s) | Value::Tag(s) => s.len(),
+            Value::Block(tokens) => tokens.iter().map(|token| match &token.kind {
+                TokenType::Literal(value) => std::mem::size_of::<Token>() + value.heap_size(),
+                _ => std::mem::size_of::<Token>(),
+            }).sum(),
+            _ => 0,
+        }
+    }
+
+    /// The name of this value's type, the same one `type` pushes
+    pub fn type_name(&self) -> &str {
+        match self {
+            Value::Block(_) => "block",
+            Value::Integer(_) => "int",
+            Value::Float(_) => "float",
+            Value::String(_) => "string",
+            Value::Boolean(_) => "bool",
+            Value::Tag(t) => t,
+            Value::Function(_) => "function",
+        }
+    }
+
+    /// Shows the value the way it would be written in the code, with blocks shortened
+    pub fn describe(&self) -> String {
+        match self {
+            Value::String(string) => format!("{:?}", string),
+            Value::Block(_) => "{...}".to_string(),
+            value => value.to_string(),
+        }
+    }
+}
+
 
 impl Display for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -57,56 +104,59 @@ impl Display for Value {
 }
 
 impl Add for Value {
-    type Output = Option<Value>;
+    type Output = Result<Value, (Value, Value)>;
     fn add(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
-            (Value::Float(v1), Value::Float(v2)) => Some(Value::Float(v1 + v2)),
-            (Value::Float(v1), Value::Integer(v2)) => Some(Value::Float(v1 + v2 as f32)),
-            (Value::Integer(v1), Value::Float(v2)) => Some(Value::Float(v1 as f32 + v2)),
-            (Value::Integer(v1), Value::Integer(v2)) => Some(Value::Integer(v1 + v2)),
-            (Value::String(v1), Value::String(v2)) => Some(Value::String(v1 + &v2)),
-            _ => None   
+            (Value::Float(v1), Value::Float(v2)) => Ok(Value::Float(v1 + v2)),
+            (Value::Float(v1), Value::Integer(v2)) => Ok(Value::Float(v1 + v2 as f32)),
+            (Value::Integer(v1), Value::Float(v2)) => Ok(Value::Float(v1 as f32 + v2)),
+            (Value::Integer(v1), Value::Integer(v2)) => Ok(Value::Integer(v1 + v2)),
+            (Value::String(v1), Value::String(v2)) => Ok(Value::String(v1 + &v2)),
+            operands => Err(operands)
         }
     }
 }
 
 impl Sub for Value {
-    type Output = Option<Value>;
+    type Output = Result<Value, (Value, Value)>;
     fn sub(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
-            (Value::Float(v1), Value::Float(v2)) => Some(Value::Float(v1 - v2)),
-            (Value::Float(v1), Value::Integer(v2)) => Some(Value::Float(v1 - v2 as f32)),
-            (Value::Integer(v1), Value::Float(v2)) => Some(Value::Float(v1 as f32 - v2)),
-            (Value::Integer(v1), Value::Integer(v2)) => Some(Value::Integer(v1 - v2)),
-            _ => None   
+            (Value::Float(v1), Value::Float(v2)) => Ok(Value::Float(v1 - v2)),
+            (Value::Float(v1), Value::Integer(v2)) => Ok(Value::Float(v1 - v2 as f32)),
+            (Value::Integer(v1), Value::Float(v2)) => Ok(Value::Float(v1 as f32 - v2)),
+            (Value::Integer(v1), Value::Integer(v2)) => Ok(Value::Integer(v1 - v2)),
+            operands => Err(operands)
         }
     }
 }
 
 impl Mul for Value {
-    type Output = Option<Value>;
+    type Output = Result<Value, (Value, Value)>;
     fn mul(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
-            (Value::Float(v1), Value::Float(v2)) => Some(Value::Float(v1 * v2)),
-            (Value::Float(v1), Value::Integer(v2)) => Some(Value::Float(v1 * v2 as f32)),
-            (Value::Integer(v1), Value::Float(v2)) => Some(Value::Float(v1 as f32 * v2)),
-            (Value::Integer(v1), Value::Integer(v2)) => Some(Value::Integer(v1 * v2)),
-            (Value::String(v1), Value::Integer(v2)) => Some(Value::String(v1.repeat(v2 as usize))), //Won't work on <32 bit address size cpu
-            _ => None   
+            (Value::Float(v1), Value::Float(v2)) => Ok(Value::Float(v1 * v2)),
+            (Value::Float(v1), Value::Integer(v2)) => Ok(Value::Float(v1 * v2 as f32)),
+            (Value::Integer(v1), Value::Float(v2)) => Ok(Value::Float(v1 as f32 * v2)),
+            (Value::Integer(v1), Value::Integer(v2)) => Ok(Value::Integer(v1 * v2)),
+            (Value::String(v1), Value::Integer(v2)) if v2 >= 0 => Ok(Value::String(v1.repeat(v2 as usize))), //Won't work on <32 bit address size cpu
+            operands => Err(operands)
         }
     }
 }
 
 impl Div for Value {
-    type Output = Option<Value>;
+    type Output = Result<Value, (Value, Value)>;
     fn div(self, rhs: Self) -> Self::Output {
         match (self, rhs) {
-            (Value::Float(v1), Value::Float(v2)) => Some(Value::Float(v1 / v2)),
-            (Value::Float(v1), Value::Integer(v2)) => Some(Value::Float(v1 / v2 as f32)),
-            (Value::Integer(v1), Value::Float(v2)) => Some(Value::Float(v1 as f32 / v2)),
-            (Value::Integer(v1), Value::Integer(v2)) => Some(Value::Float(v1 as f32 / v2 as f32)),
-            (Value::String(v1), Value::Integer(v2)) => match v1.chars().nth((v2 as usize) - 1) {Some(s) => Some(Value::String(s.to_string())), None => None}
-            _ => None   
+            (Value::Float(v1), Value::Float(v2)) => Ok(Value::Float(v1 / v2)),
+            (Value::Float(v1), Value::Integer(v2)) => Ok(Value::Float(v1 / v2 as f32)),
+            (Value::Integer(v1), Value::Float(v2)) => Ok(Value::Float(v1 as f32 / v2)),
+            (Value::Integer(v1), Value::Integer(v2)) => Ok(Value::Float(v1 as f32 / v2 as f32)),
+            (Value::String(v1), Value::Integer(v2)) => match v1.chars().nth((v2 as usize).wrapping_sub(1)) {
+                Some(s) => Ok(Value::String(s.to_string())),
+                None => Err((Value::String(v1), Value::Integer(v2))),
+            },
+            operands => Err(operands)
         }
     }
 }
@@ -128,11 +178,11 @@ impl PartialEq for Value {
 }
 
 impl Not for Value {
-    type Output = Option<Value>;
+    type Output = Result<Value, Value>;
     fn not(self) -> Self::Output {
         match self {
-            Value::Boolean(b) => Some(Value::Boolean(!b)),
-            _ => None,
+            Value::Boolean(b) => Ok(Value::Boolean(!b)),
+            value => Err(value),
         }
     }
 }
@@ -152,6 +202,197 @@ impl PartialOrd for Value {
 }
 
 
+/// A named function's declared stack effect, eg. `@greet ( name string -- string )`
+/// 
+/// Each parameter is either a type name, which the value has to be, or a label for any value.
+/// An output with the same label as an input is that input, left where it was.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Signature {
+    /// The function it belongs to, for errors
+    pub name: String,
+    /// What the function takes, bottom of the stack first
+    pub inputs: Vec<String>,
+    /// What the function leaves, bottom of the stack first
+    pub outputs: Vec<String>,
+}
+
+impl Signature {
+    /// The type a parameter declares, or `None` for a label that can be any value
+    pub fn param_type(param: &str) -> Option<&str> {
+        match param {
+            "int" | "float" | "number" | "string" | "bool" | "block" | "function" => Some(param),
+            _ => None,
+        }
+    }
+
+    /// Whether a value can be given for a parameter
+    pub fn accepts(param: &str, value: &Value) -> bool {
+        match Self::param_type(param) {
+            Some("number") => matches!(value, Value::Integer(_) | Value::Float(_)),
+            Some(ty) => value.type_name() == ty,
+            None => true,
+        }
+    }
+
+    /// Describes what a list of parameters needs, eg. "int and a value"
+    pub fn describe(params: &[String]) -> String {
+        if params.is_empty() {
+            return "nothing".to_string();
+        }
+        let names: Vec<&str> = params.iter().map(|param| Self::param_type(param).unwrap_or("a value")).collect();
+        names.join(" and ")
+    }
+}
+
+impl Display for Signature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "(")?;
+        for input in &self.inputs {
+            write!(f, " {}", input)?;
+        }
+        write!(f, " --")?;
+        for output in &self.outputs {
+            write!(f, " {}", output)?;
+        }
+        write!(f, " )")
+    }
+}
+
+/// Writes a string as its length (4 bytes) followed by its UTF-8 bytes
+fn string_to_bytes(string: &str, bytes: &mut Vec<u8>) {
+    bytes.extend_from_slice(&(string.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(string.as_bytes());
+}
+
+/// Reads a string written by `string_to_bytes`
+/// 
+/// **Returns:** The string, and how many bytes were read
+fn string_from_bytes(bytes: &[u8]) -> Result<(String, usize), SerializationError> {
+    if bytes.len() < 4 {
+        return Err(SerializationError::EndOfFile);
+    }
+    let len = u32::from_be_bytes(bytes[0..4].try_into().unwrap()) as usize; //Unwrap is okay because we checked the length
+    if bytes.len() < 4 + len {
+        return Err(SerializationError::EndOfFile);
+    }
+    match String::from_utf8(bytes[4..4 + len].to_vec()) {
+        Ok(s) => Ok((s, 4 + len)),
+        Err(e) => Err(SerializationError::InvalidUTF8Encoding(e)),
+    }
+}
+
+impl ByteSized for Signature {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        string_to_bytes(&self.name, &mut bytes);
+        //Each list is its length (4 bytes), then each parameter
+        for params in [&self.inputs, &self.outputs] {
+            bytes.extend_from_slice(&(params.len() as u32).to_be_bytes());
+            for param in params {
+                string_to_bytes(param, &mut bytes);
+            }
+        }
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<(Self, usize), SerializationError>
+        where
+            Self: Sized
+    {
+        let (name, mut offset) = string_from_bytes(bytes)?;
+        let mut lists = [Vec::new(), Vec::new()];
+        for params in &mut lists {
+            if bytes.len() < offset + 4 {
+                return Err(SerializationError::EndOfFile);
+            }
+            let count = u32::from_be_bytes(bytes[offset..offset + 4].try_into().unwrap()); //Unwrap is okay because we checked the length
+            offset += 4;
+            for _ in 0..count {
+                let (param, read) = string_from_bytes(&bytes[offset..])?;
+                offset += read;
+                params.push(param);
+            }
+        }
+        let [inputs, outputs] = lists;
+        Ok((Signature {name, inputs, outputs}, offset))
+    }
+}
+
+/// Something outside of the interpreter that code can use
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Capability {
+    Stdin,
+    Stdout,
+    FileRead,
+    FileWrite,
+    LibraryLoading,
+    Env,
+    Clock,
+    Random,
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Capability::Stdin => write!(f, "stdin"),
+            Capability::Stdout => write!(f, "stdout"),
+            Capability::FileRead => write!(f, "file read"),
+            Capability::FileWrite => write!(f, "file write"),
+            Capability::LibraryLoading => write!(f, "library loading"),
+            Capability::Env => write!(f, "env"),
+            Capability::Clock => write!(f, "clock"),
+            Capability::Random => write!(f, "random"),
+        }
+    }
+}
+
+/// The capabilities that code is allowed to use
+/// 
+/// Everything is allowed by default, use `Permissions::none()` and `allow` for untrusted code.
+#[derive(Debug, Clone)]
+pub struct Permissions {
+    allowed: HashSet<Capability>,
+}
+
+impl Permissions {
+    /// Allows every capability
+    pub fn all() -> Self {
+        Permissions {allowed: HashSet::from([
+            Capability::Stdin,
+            Capability::Stdout,
+            Capability::FileRead,
+            Capability::FileWrite,
+            Capability::LibraryLoading,
+            Capability::Env,
+            Capability::Clock,
+            Capability::Random,
+        ])}
+    }
+
+    /// Allows no capabilities
+    pub fn none() -> Self {
+        Permissions {allowed: HashSet::new()}
+    }
+
+    pub fn allow(&mut self, capability: Capability) {
+        self.allowed.insert(capability);
+    }
+
+    pub fn deny(&mut self, capability: Capability) {
+        self.allowed.remove(&capability);
+    }
+
+    pub fn is_allowed(&self, capability: Capability) -> bool {
+        self.allowed.contains(&capability)
+    }
+}
+
+impl Default for Permissions {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
 #[derive(Debug)]
 #[derive(Clone)]
 pub enum Operation {
@@ -171,6 +412,70 @@ pub enum Operation {
     Run,
 }
 
+/// Shows the keyword the way it is written in source code
+impl Display for Keyword {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Keyword::PRINT => "print",
+            Keyword::TRUE => "true",
+            Keyword::FALSE => "false",
+            Keyword::EXIT => "exit",
+            Keyword::LOOP => "loop",
+            Keyword::GATE => "gate",
+            Keyword::DUPLICATE => "dup",
+            Keyword::DROP => "drop",
+            Keyword::SWAP => "swap",
+            Keyword::DEPTH => "depth",
+            Keyword::ROT => "rot",
+            Keyword::NROT => "nrot",
+            Keyword::OVER => "over",
+            Keyword::TUCK => "tuck",
+            Keyword::PICK => "pick",
+            Keyword::ROLL => "roll",
+            Keyword::CLEAR => "clear",
+            Keyword::TYPE => "type",
+            Keyword::USE => "use",
+            Keyword::INPUT => "input",
+            Keyword::STRLEN => "strlen",
+            Keyword::ASSERT => "assert",
+            Keyword::READ => "read",
+            Keyword::READLINES => "readlines",
+            Keyword::WRITE => "write",
+            Keyword::APPEND => "append",
+            Keyword::EXISTS => "exists",
+            Keyword::DELETE => "delete",
+            Keyword::TRY => "try",
+            Keyword::ARGS => "args",
+            Keyword::ENV => "env",
+            Keyword::BREAK => "break",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Shows the operator the way it is written in source code
+impl Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let symbol = match self {
+            Operation::Add => "+",
+            Operation::Subtract => "-",
+            Operation::Multiply => "*",
+            Operation::Divide => "/",
+            Operation::Equal => "=",
+            Operation::NotEqual => "!=",
+            Operation::Greater => ">",
+            Operation::Lesser => "<",
+            Operation::GreaterEqual => ">=",
+            Operation::LesserEqual => "<=",
+            Operation::And => "&",
+            Operation::Or => "|",
+            Operation::Not => "!",
+            Operation::Run => "$",
+        };
+        write!(f, "{}", symbol)
+    }
+}
+
 impl ByteSized for Keyword {
     fn to_bytes(&self) -> Vec<u8> {
 
@@ -196,6 +501,17 @@ impl ByteSized for Keyword {
             Keyword::USE => 0x13,
             Keyword::INPUT => 0x14,
             Keyword::STRLEN => 0x15,
+            Keyword::ASSERT => 0x16,
+            Keyword::READ => 0x17,
+            Keyword::READLINES => 0x18,
+            Keyword::WRITE => 0x19,
+            Keyword::APPEND => 0x1A,
+            Keyword::EXISTS => 0x1B,
+            Keyword::DELETE => 0x1C,
+            Keyword::TRY => 0x1D,
+            Keyword::ARGS => 0x1E,
+            Keyword::ENV => 0x1F,
+            Keyword::BREAK => 0x20,
         };
         vec![binary]
     }
@@ -231,6 +547,17 @@ impl ByteSized for Keyword {
             0x13 => Keyword::USE,
             0x14 => Keyword::INPUT,
             0x15 => Keyword::STRLEN,
+            0x16 => Keyword::ASSERT,
+            0x17 => Keyword::READ,
+            0x18 => Keyword::READLINES,
+            0x19 => Keyword::WRITE,
+            0x1A => Keyword::APPEND,
+            0x1B => Keyword::EXISTS,
+            0x1C => Keyword::DELETE,
+            0x1D => Keyword::TRY,
+            0x1E => Keyword::ARGS,
+            0x1F => Keyword::ENV,
+            0x20 => Keyword::BREAK,
             _ => return Err(SerializationError::InvalidTagByte(tag))
         };
 
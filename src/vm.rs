@@ -1,467 +1,1034 @@
-use std::{collections::HashMap, error::Error, fmt::Display};
+use std::{borrow::Cow, cell::RefCell, collections::HashMap, error::Error, fmt::Display, io::Write, path::PathBuf, rc::Rc, time::{Duration, Instant}};
 
-use crate::{lexer::{Token, TokenPosition, TokenType}, types::{Keyword, Operation, Value}};
+use crate::{diagnostic::{ColorChoice, ErrorFormat}, lexer::{Token, TokenPosition, TokenType}, types::{Capability, Keyword, Operation, Permissions, Signature, Value}};
+
+/// A value on the stack, with where it was pushed
+type Entry = (Value, TokenPosition);
+
+/// Code that is about to be run, with the name of the function it came from
+type Callee<'a> = (Cow<'a, [Token]>, Option<&'a str>);
 
 #[derive(Debug)]
 pub struct Stack {
-    data: Vec<Value>,
+    data: Vec<Entry>, //Each value keeps where it was pushed, for errors
+    bytes: usize, //Memory used by the values, kept up to date for memory limits
 }
 
 impl Stack {
     pub fn new() -> Self {
-        Stack {data: Vec::new()}
+        Stack {data: Vec::new(), bytes: 0}
     }
 
-    fn push(&mut self, val: Value) {
-        self.data.push(val);
+    fn push(&mut self, val: Value, origin: TokenPosition) {
+        self.bytes += val.heap_size();
+        self.data.push((val, origin));
     }
 
     fn pop(&mut self) -> Option<Value> {
-        self.data.pop()
+        self.pop_with_origin().map(|(val, _)| val)
+    }
+
+    fn pop_with_origin(&mut self) -> Option<(Value, TokenPosition)> {
+        let (val, origin) = self.data.pop()?;
+        self.bytes -= val.heap_size();
+        Some((val, origin))
+    }
+
+    fn insert(&mut self, index: usize, val: Value, origin: TokenPosition) {
+        self.bytes += val.heap_size();
+        self.data.insert(index, (val, origin));
+    }
+
+    fn remove(&mut self, index: usize) -> (Value, TokenPosition) {
+        let (val, origin) = self.data.remove(index);
+        self.bytes -= val.heap_size();
+        (val, origin)
+    }
+
+    fn clear(&mut self) {
+        self.data.clear();
+        self.bytes = 0;
+    }
+
+    /// The values from the bottom of the stack to the top
+    pub fn values(&self) -> impl Iterator<Item = &Value> {
+        self.data.iter().map(|(val, _)| val)
+    }
+
+}
+
+impl Default for Stack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Follows stackathon code as it runs
+/// 
+/// Every method does nothing by default, so only the ones that are needed have to be written.
+/// Tokens are given as something to show, the way they would be written in the code.
+pub trait Tracer {
+    /// Called before each token runs
+    /// 
+    /// **Returns:** `false` to stop the program with a `Stopped` error
+    fn before(&mut self, _token: &dyn Display, _pos: TokenPosition, _stack: &Stack) -> bool {
+        true
     }
 
+    /// Called after each token runs, including any function or block it ran.
+    /// A tail call is the exception, as it reuses the frame, so this is called before it starts.
+    fn after(&mut self, _token: &dyn Display, _pos: TokenPosition, _stack: &Stack) {}
+
+    /// Called when a function or block starts running
+    /// 
+    /// **Arguments:**
+    /// * `name`: The function's name, or `None` for a block
+    /// * `called_at`: Where it was called
+    /// * `tail`: Whether it is a tail call, which replaces the frame that called it
+    fn enter(&mut self, _name: Option<&str>, _called_at: TokenPosition, _tail: bool) {}
+
+    /// Called when a function or block returns. A chain of tail calls reuses one frame, so it only
+    /// returns once, and the code run by `execute` returns only if it made a tail call.
+    fn leave(&mut self) {}
+
+    /// Called when `break` runs
+    fn interrupt(&mut self, _pos: TokenPosition) {}
+
+    /// Called when a `gate` has decided which way to go, `true` for the block on top
+    fn branch(&mut self, _pos: TokenPosition, _taken: bool) {}
+}
+
+impl std::fmt::Debug for dyn Tracer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Tracer")
+    }
+}
+
+/// The settings and state for running stackathon code
+/// 
+/// The limits are off by default. They are counted from the start of each run, eg. each call to
+/// `run_string_with_context` or each test, which makes them useful for running untrusted code.
+#[derive(Debug, Clone)]
+pub struct Context {
+    /// How deeply blocks and functions can be nested before a `StackOverflow` error.
+    /// Tail calls don't count towards this.
+    pub max_call_depth: usize,
+    /// How many steps can be run. Each token and each cycle of a `loop` is a step.
+    pub max_steps: Option<u64>,
+    /// How many values can be on the stack at once
+    pub max_stack_depth: Option<usize>,
+    /// How many bytes of strings and blocks can be on the stack at once
+    pub max_memory: Option<usize>,
+    /// How long the code can run for
+    pub timeout: Option<Duration>,
+    /// What the code is allowed to use, eg. stdin or files
+    pub permissions: Permissions,
+    /// The arguments given to the script, pushed by `args`
+    pub args: Vec<String>,
+    /// Directories `use` looks for libraries in, after the importing file's directory and `STACKATHON_PATH`
+    pub lib_paths: Vec<PathBuf>,
+    /// How errors are shown
+    pub error_format: ErrorFormat,
+    /// When errors are shown in color
+    pub color: ColorChoice,
+    /// Whether functions are checked against their declared stack effects when they are called and return
+    pub debug: bool,
+    /// Follows the code as it runs, eg. to log it or pause it in the debugger
+    pub tracer: Option<Rc<RefCell<dyn Tracer>>>,
+    depth: usize,
+    steps: u64,
+    start: Option<Instant>,
+}
+
+impl Context {
+    pub fn new() -> Self {
+        Context {
+            max_call_depth: 1000,
+            max_steps: None,
+            max_stack_depth: None,
+            max_memory: None,
+            timeout: None,
+            permissions: Permissions::all(),
+            args: Vec::new(),
+            lib_paths: Vec::new(),
+            error_format: ErrorFormat::Text,
+            color: ColorChoice::Auto,
+            debug: false,
+            tracer: None,
+            depth: 0,
+            steps: 0,
+            start: None,
+        }
+    }
+
+    /// Starts counting the limits again, eg. before running more code with the same context
+    pub fn reset(&mut self) {
+        self.depth = 0;
+        self.steps = 0;
+        self.start = None;
+    }
+
+    /// Checks that the code is allowed to use a capability
+    fn require(&self, capability: Capability, pos: TokenPosition) -> Result<(), RuntimeError> {
+        if self.permissions.is_allowed(capability) {
+            Ok(())
+        } else {
+            Err(RuntimeError::PermissionDenied(pos, capability))
+        }
+    }
+
+    /// Counts a step, and checks that none of the limits have been passed
+    /// 
+    /// **Arguments:**
+    /// * `stack`: The stack the code is running on
+    /// * `pos`: The position of the code being run
+    fn step(&mut self, stack: &Stack, pos: TokenPosition) -> Result<(), RuntimeError> {
+        self.steps += 1;
+        if let Some(max_steps) = self.max_steps && self.steps > max_steps {
+            return Err(RuntimeError::StepLimitExceeded(pos, max_steps));
+        }
+        if let Some(max_stack_depth) = self.max_stack_depth && stack.data.len() > max_stack_depth {
+            return Err(RuntimeError::StackLimitExceeded(pos, max_stack_depth));
+        }
+        if let Some(max_memory) = self.max_memory && stack.bytes > max_memory {
+            return Err(RuntimeError::MemoryLimitExceeded(pos, max_memory));
+        }
+        if let Some(timeout) = self.timeout {
+            let start = *self.start.get_or_insert_with(Instant::now);
+            if start.elapsed() > timeout {
+                return Err(RuntimeError::Timeout(pos, timeout));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What an operator or keyword needed, and what it was given instead
+#[derive(Debug)]
+pub struct Mismatch {
+    /// What was needed, eg. "a block on top"
+    pub expected: &'static str,
+    /// What was given, bottom of the stack first, with where each value was pushed.
+    /// Empty if the stack ran out of values.
+    pub found: Vec<(String, TokenPosition)>,
+}
+
+impl Mismatch {
+    fn new(expected: &'static str, found: &[(&Value, TokenPosition)]) -> Self {
+        Mismatch {
+            expected,
+            found: found.iter().map(|(value, origin)| (value.type_name().to_string(), *origin)).collect(),
+        }
+    }
+
+    /// For when there aren't enough values, lists every value that there is
+    fn too_few(expected: &'static str, stack: &Stack) -> Self {
+        let found: Vec<(&Value, TokenPosition)> = stack.data.iter().map(|(value, origin)| (value, *origin)).collect();
+        Self::new(expected, &found)
+    }
+}
+
+impl Display for Mismatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.found.is_empty() {
+            return write!(f, "expected {}, found an empty stack", self.expected);
+        }
+        write!(f, "expected {}, found {}", self.expected, type_names(&self.found))
+    }
 }
 
+/// Lists the types of some values, eg. "int and string"
+fn type_names(found: &[(String, TokenPosition)]) -> String {
+    let names: Vec<&str> = found.iter().map(|(name, _)| name.as_str()).collect();
+    names.join(" and ")
+}
 
 #[derive(Debug)]
 pub enum RuntimeError {
-    OperatorInvalidValues(TokenPosition, char),
-    KeywordInvalidValues(TokenPosition, Keyword),
+    OperatorInvalidValues(TokenPosition, Operation, Mismatch),
+    KeywordInvalidValues(TokenPosition, Keyword, Mismatch),
     InputError(TokenPosition),
+    AssertionFailed(TokenPosition, Option<String>),
+    UnknownFunction(TokenPosition, String),
+    StackOverflow(TokenPosition, usize),
+    StepLimitExceeded(TokenPosition, u64),
+    StackLimitExceeded(TokenPosition, usize),
+    MemoryLimitExceeded(TokenPosition, usize),
+    Timeout(TokenPosition, Duration),
+    PermissionDenied(TokenPosition, Capability),
+    FileError(TokenPosition, String, std::io::Error),
+    /// A function was called with values that don't fit its declared stack effect
+    InvalidArguments(TokenPosition, String, String, Vec<(String, TokenPosition)>),
+    /// A function returned values that don't fit its declared stack effect
+    InvalidResults(TokenPosition, String, String, Vec<(String, TokenPosition)>),
+    /// The program was stopped by a tracer, eg. from the debugger
+    Stopped(TokenPosition),
 }
 
 impl RuntimeError {
     pub fn position(&self) -> TokenPosition {
         match self {
-            RuntimeError::OperatorInvalidValues(pos, _) => *pos,
-            RuntimeError::KeywordInvalidValues(pos, _) => *pos,
+            RuntimeError::OperatorInvalidValues(pos, _, _) => *pos,
+            RuntimeError::KeywordInvalidValues(pos, _, _) => *pos,
             RuntimeError::InputError(pos) => *pos,
+            RuntimeError::AssertionFailed(pos, _) => *pos,
+            RuntimeError::UnknownFunction(pos, _) => *pos,
+            RuntimeError::StackOverflow(pos, _) => *pos,
+            RuntimeError::StepLimitExceeded(pos, _) => *pos,
+            RuntimeError::StackLimitExceeded(pos, _) => *pos,
+            RuntimeError::MemoryLimitExceeded(pos, _) => *pos,
+            RuntimeError::Timeout(pos, _) => *pos,
+            RuntimeError::PermissionDenied(pos, _) => *pos,
+            RuntimeError::FileError(pos, _, _) => *pos,
+            RuntimeError::InvalidArguments(pos, _, _, _) => *pos,
+            RuntimeError::InvalidResults(pos, _, _, _) => *pos,
+            RuntimeError::Stopped(pos) => *pos,
+        }
+    }
+
+    /// What kind of error this is, shown before its code
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RuntimeError::AssertionFailed(_, _) => "Assertion failed",
+            _ => "Runtime Error",
+        }
+    }
+
+    /// The stable code for this error, listed in the README
+    pub fn code(&self) -> &'static str {
+        match self {
+            RuntimeError::OperatorInvalidValues(_, _, _) => "E0201",
+            RuntimeError::KeywordInvalidValues(_, _, _) => "E0202",
+            RuntimeError::InputError(_) => "E0203",
+            RuntimeError::AssertionFailed(_, _) => "E0204",
+            RuntimeError::UnknownFunction(_, _) => "E0205",
+            RuntimeError::StackOverflow(_, _) => "E0206",
+            RuntimeError::StepLimitExceeded(_, _) => "E0207",
+            RuntimeError::StackLimitExceeded(_, _) => "E0208",
+            RuntimeError::MemoryLimitExceeded(_, _) => "E0209",
+            RuntimeError::Timeout(_, _) => "E0210",
+            RuntimeError::PermissionDenied(_, _) => "E0211",
+            RuntimeError::FileError(_, _, _) => "E0212",
+            RuntimeError::InvalidArguments(_, _, _, _) => "E0213",
+            RuntimeError::InvalidResults(_, _, _, _) => "E0214",
+            RuntimeError::Stopped(_) => "E0215",
+        }
+    }
+
+    /// The error's description, without its kind or position
+    pub fn message(&self) -> String {
+        match self {
+            Self::OperatorInvalidValues(_, op, mismatch) =>
+                format!("`{}` {}", op, mismatch),
+            Self::KeywordInvalidValues(_, k, mismatch) =>
+                format!("`{}` {}", k, mismatch),
+            Self::InputError(_) =>
+                "Issue with getting user input.".to_string(),
+            Self::AssertionFailed(_, Some(message)) =>
+                message.clone(),
+            Self::AssertionFailed(_, None) =>
+                "The condition was false.".to_string(),
+            Self::UnknownFunction(_, name) =>
+                format!("Function '{}' is not defined.", name),
+            Self::StackOverflow(_, depth) =>
+                format!("Stack overflow, calls were nested deeper than {}.", depth),
+            Self::StepLimitExceeded(_, steps) =>
+                format!("Step limit of {} exceeded.", steps),
+            Self::StackLimitExceeded(_, depth) =>
+                format!("Stack limit of {} values exceeded.", depth),
+            Self::MemoryLimitExceeded(_, bytes) =>
+                format!("Memory limit of {} bytes exceeded.", bytes),
+            Self::Timeout(_, timeout) =>
+                format!("Timed out after {:?}.", timeout),
+            Self::PermissionDenied(_, capability) =>
+                format!("Permission denied, the {} capability is not allowed.", capability),
+            Self::FileError(_, path, e) =>
+                format!("File error with '{}': {}", path, e),
+            Self::InvalidArguments(_, name, expected, found) if found.is_empty() =>
+                format!("`{}` expected {}, found an empty stack", name, expected),
+            Self::InvalidArguments(_, name, expected, found) =>
+                format!("`{}` expected {}, found {}", name, expected, type_names(found)),
+            Self::InvalidResults(_, name, expected, found) if found.is_empty() =>
+                format!("`{}` should leave {}, but left nothing", name, expected),
+            Self::InvalidResults(_, name, expected, found) =>
+                format!("`{}` should leave {}, but left {}", name, expected, type_names(found)),
+            Self::Stopped(_) =>
+                "Stopped before finishing.".to_string(),
+        }
+    }
+
+    /// The values that caused the error, with where each one was pushed
+    pub fn culprits(&self) -> &[(String, TokenPosition)] {
+        match self {
+            Self::OperatorInvalidValues(_, _, mismatch) | Self::KeywordInvalidValues(_, _, mismatch) => &mismatch.found,
+            Self::InvalidArguments(_, _, _, found) | Self::InvalidResults(_, _, _, found) => found,
+            _ => &[],
         }
     }
 }
 
 impl Display for RuntimeError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Self::OperatorInvalidValues(pos, op) =>
-                write!(f,"Runtime Error({}:{}): Incorrect values provided for operator '{}'", pos.col, pos.row, op),
-            Self::KeywordInvalidValues(pos, k) =>
-                write!(f,"Runtime Error({}:{}): Incorrect values provided for keyword '{:?}'", pos.col, pos.row, k),
-            Self::InputError(pos) =>
-                write!(f, "Runtime Error({}:{}): Issue with getting user input.", pos.col, pos.row),
-        }
+        let pos = self.position();
+        write!(f, "{}[{}]({}:{}): {}", self.kind(), self.code(), pos.row, pos.col, self.message())
     }
 }
 
 impl Error for RuntimeError {}
 
-pub fn execute(tokens: &Vec<Token>, stack: &mut Stack, function_table: &HashMap<String, Vec<Token>>) -> Result<(), RuntimeError>{
+fn into_bool(value: Value) -> Result<bool, Value> {
+    match value {
+        Value::Boolean(b) => Ok(b),
+        value => Err(value),
+    }
+}
 
-    for token in tokens {
-        match &token.kind {
-            TokenType::Literal(lit) => match lit {
-                Value::Function(func) => {
-                    let function_definition = function_table.get(func).unwrap();
+fn into_string(value: Value) -> Result<String, Value> {
+    match value {
+        Value::String(s) => Ok(s),
+        value => Err(value),
+    }
+}
 
-                    if function_definition.is_empty() {
-                        stack.push(Value::Tag(func.clone()));
-                    } else {
-                        stack.push(Value::Block(function_definition.clone()));
+/// Pops the top value, along with where it was pushed
+/// 
+/// **Arguments:**
+/// * `stack`: The stack to pop from
+/// * `error`: Makes the error to return if the stack is empty
+/// * `expected`: What the operator or keyword needed, for the error
+fn pop_value(stack: &mut Stack, error: impl FnOnce(Mismatch) -> RuntimeError, expected: &'static str) -> Result<(Value, TokenPosition), RuntimeError> {
+    stack.pop_with_origin().ok_or_else(|| error(Mismatch::new(expected, &[])))
+}
+
+/// Pops the top value as a specific type
+/// 
+/// **Arguments:**
+/// * `stack`: The stack to pop from
+/// * `convert`: Turns the value into the type, or gives it back if it is the wrong type
+/// * `error`: Makes the error to return if the value is missing or the wrong type
+/// * `expected`: What the operator or keyword needed, for the error
+fn pop_as<T>(stack: &mut Stack, convert: impl FnOnce(Value) -> Result<T, Value>, error: impl FnOnce(Mismatch) -> RuntimeError, expected: &'static str) -> Result<T, RuntimeError> {
+    match stack.pop_with_origin() {
+        Some((value, origin)) => convert(value).map_err(|value| error(Mismatch::new(expected, &[(&value, origin)]))),
+        None => Err(error(Mismatch::new(expected, &[]))),
+    }
+}
+
+/// Pops the top two values for an operator
+/// 
+/// **Returns:** The value below, then the value on top, each with where it was pushed
+fn pop_two(stack: &mut Stack, error: impl FnOnce(Mismatch) -> RuntimeError, expected: &'static str) -> Result<(Entry, Entry), RuntimeError> {
+    if stack.data.len() < 2 {
+        return Err(error(Mismatch::too_few(expected, stack)));
+    }
+    let top = stack.pop_with_origin().expect("stack has two values");
+    let below = stack.pop_with_origin().expect("stack has two values");
+    Ok((below, top))
+}
+
+/// Applies an operator to the top two values, and pushes the result
+/// 
+/// **Arguments:**
+/// * `stack`: The stack to run the operator on
+/// * `pos`: Where the operator is
+/// * `error`: Makes the error to return if the values are missing or don't fit together
+/// * `expected`: What the operator needs, for the error
+/// * `operator`: Takes the value below and the value on top, and gives them back if they don't fit together
+fn apply(stack: &mut Stack, pos: TokenPosition, error: impl Fn(Mismatch) -> RuntimeError, expected: &'static str, operator: impl FnOnce(Value, Value) -> Result<Value, (Value, Value)>) -> Result<(), RuntimeError> {
+    let ((below, below_origin), (top, top_origin)) = pop_two(stack, &error, expected)?;
+    match operator(below, top) {
+        Ok(value) => {
+            stack.push(value, pos);
+            Ok(())
+        },
+        Err((below, top)) => Err(error(Mismatch::new(expected, &[(&below, below_origin), (&top, top_origin)]))),
+    }
+}
+
+/// Gets the code to run for a block or a named function
+/// 
+/// Named functions stay as references on the stack, so they are only looked up when they are run.
+/// 
+/// **Arguments:**
+/// * `value`: The value that should be run, with where it was pushed
+/// * `function_table`: The table to look named functions up in
+/// * `pos`: Where the value is being run
+/// * `error`: Makes the error to return if the value can't be run
+/// * `expected`: What the operator or keyword needed, for the error
+/// 
+/// **Returns:** The code, and the name of the function it is the body of, if it isn't a block
+fn into_body<'a>(value: (Value, TokenPosition), function_table: &'a HashMap<String, Vec<Token>>, pos: TokenPosition, error: impl FnOnce(Mismatch) -> RuntimeError, expected: &'static str) -> Result<Callee<'a>, RuntimeError> {
+    match value {
+        (Value::Block(b), _) => Ok((Cow::Owned(b), None)),
+        (Value::Function(name), _) => match function_table.get_key_value(&name) {
+            Some((name, body)) => Ok((Cow::Borrowed(body), Some(name))),
+            None => Err(RuntimeError::UnknownFunction(pos, name)),
+        },
+        (value, origin) => Err(error(Mismatch::new(expected, &[(&value, origin)]))),
+    }
+}
+
+/// Pops a block or named function, and gets the code to run for it
+fn pop_body<'a>(stack: &mut Stack, function_table: &'a HashMap<String, Vec<Token>>, pos: TokenPosition, error: impl FnOnce(Mismatch) -> RuntimeError + Copy, expected: &'static str) -> Result<Callee<'a>, RuntimeError> {
+    let value = pop_value(stack, error, expected)?;
+    into_body(value, function_table, pos, error, expected)
+}
+
+/// What a function declared it would leave, checked when it returns
+#[derive(PartialEq)]
+struct Contract {
+    signature: Signature,
+    /// Where the function was called
+    pos: TokenPosition,
+    /// How many values were on the stack below the function's inputs
+    base: usize,
+}
+
+/// The values in a part of the stack, with where each one was pushed, for errors
+fn describe_values(stack: &Stack, from: usize) -> Vec<(String, TokenPosition)> {
+    stack.data[from..].iter().map(|(value, origin)| (value.type_name().to_string(), *origin)).collect()
+}
+
+/// Checks that the stack has the values a function declared it takes
+/// 
+/// **Returns:** What the function promised to leave, to be checked when it returns
+fn enter(stack: &Stack, signature: &Signature, pos: TokenPosition) -> Result<Contract, RuntimeError> {
+    let inputs = &signature.inputs;
+    let base = stack.data.len().saturating_sub(inputs.len());
+    let fits = stack.data.len() >= inputs.len() && inputs.iter().zip(&stack.data[base..])
+        .all(|(param, (value, _))| Signature::accepts(param, value));
+    if !fits {
+        return Err(RuntimeError::InvalidArguments(pos, signature.name.clone(), Signature::describe(inputs), describe_values(stack, base)));
+    }
+    Ok(Contract {signature: signature.clone(), pos, base})
+}
+
+/// Checks that a function left the values it declared
+fn leave(stack: &Stack, contract: &Contract) -> Result<(), RuntimeError> {
+    let outputs = &contract.signature.outputs;
+    let base = contract.base.min(stack.data.len());
+    let fits = stack.data.len() == contract.base + outputs.len() && outputs.iter().zip(&stack.data[base..])
+        .all(|(param, (value, _))| Signature::accepts(param, value));
+    if !fits {
+        return Err(RuntimeError::InvalidResults(contract.pos, contract.signature.name.clone(), Signature::describe(outputs), describe_values(stack, base)));
+    }
+    Ok(())
+}
+
+/// Runs tokens on a stack
+/// 
+/// Running a block or function as the last token of the code (including from a `gate` branch)
+/// is a tail call, which reuses the current frame instead of counting towards the call depth.
+/// 
+/// In debug mode, functions with a declared stack effect are checked when they start, and again
+/// when the frame returns, which for a tail call is when the last function in the chain returns.
+/// 
+/// **Arguments:**
+/// * `tokens`: The code to run
+/// * `stack`: The stack to run the code on
+/// * `function_table`: The named functions the code can use
+/// * `context`: The settings and state of this run
+pub fn execute<'a>(tokens: &'a [Token], stack: &mut Stack, function_table: &'a HashMap<String, Vec<Token>>, context: &mut Context) -> Result<(), RuntimeError>{
+    //Frames are kept here rather than on the native stack, so deep nesting ends in a `StackOverflow` error
+    let mut frames = vec![Frame {code: Rc::new(Cow::Borrowed(tokens)), next: 0, called_at: None, contracts: Vec::new(), then: Then::Finish}];
+    while let Some(frame) = frames.last_mut() {
+        let mut pending = match run_frame(frame, stack, function_table, context) {
+            Ok(Some(call)) => Ok(Some(call)),
+            Ok(None) => return_from(&mut frames, stack, context),
+            Err(e) => Err(e),
+        };
+        loop {
+            pending = match pending {
+                Ok(None) => break,
+                Ok(Some(call)) => push_call(&mut frames, call, context).map(|_| None),
+                Err(e) => Ok(unwind(&mut frames, e, stack, context)?),
+            };
+        }
+    }
+    Ok(())
+}
+
+/// A block or function being run
+struct Frame<'a> {
+    code: Rc<Cow<'a, [Token]>>,
+    /// The index of the token being run
+    next: usize,
+    /// Where the code was called from, for errors about declared stack effects
+    called_at: Option<TokenPosition>,
+    contracts: Vec<Contract>,
+    /// What the calling token does once this frame returns
+    then: Then<'a>,
+}
+
+/// What a token that called a block or function does once the call returns
+enum Then<'a> {
+    /// Moves on to the next token
+    Finish,
+    /// Runs the `loop` body again if there is a `true` on top
+    Loop(Rc<Cow<'a, [Token]>>, Option<&'a str>),
+    /// Runs the `try` handler if the call failed to read a file or input
+    Try(Rc<Cow<'a, [Token]>>, Option<&'a str>),
+}
+
+/// A call that a frame wants to make
+struct Call<'a> {
+    body: Rc<Cow<'a, [Token]>>,
+    name: Option<&'a str>,
+    pos: TokenPosition,
+    then: Then<'a>,
+}
+
+/// Runs the top frame until it returns or calls a block or function, see `execute`
+/// 
+/// **Returns:** The call to make, or `None` if the frame returned
+fn run_frame<'a>(frame: &mut Frame<'a>, stack: &mut Stack, function_table: &'a HashMap<String, Vec<Token>>, context: &mut Context) -> Result<Option<Call<'a>>, RuntimeError> {
+    loop {
+        let code = Rc::clone(&frame.code);
+        let mut tail_call = None;
+        while let Some(token) = code.get(frame.next) {
+            let is_tail = frame.next == code.len() - 1;
+            if let Some(tracer) = &context.tracer && !tracer.borrow_mut().before(&token.kind, token.pos, stack) {
+                return Err(RuntimeError::Stopped(token.pos));
+            }
+            context.step(stack, token.pos)?;
+            match &token.kind {
+                TokenType::Literal(lit) => match lit {
+                    Value::Function(func) => {
+                        //Tags are functions without a body
+                        match function_table.get(func) {
+                            Some(function_definition) if function_definition.is_empty() => stack.push(Value::Tag(func.clone()), token.pos),
+                            _ => stack.push(lit.clone(), token.pos),
+                        }
+                    },
+                    _ => stack.push(lit.clone(), token.pos)
+                },
+                TokenType::Signature(signature) => if context.debug {
+                    let contract = enter(stack, signature, frame.called_at.unwrap_or(token.pos))?;
+                    //A recursive tail call makes the same promise each time, so it is only kept once
+                    if !frame.contracts.contains(&contract) {
+                        frame.contracts.push(contract);
                     }
                 },
-                _ => stack.push(lit.clone())
-            },
-            TokenType::Op(op) => {
-
-                match op {
-                    Operation::Add => {
-                        let val1 = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '+'))
-                        };
-                        let val2 = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '+'))
-                        };
-                        stack.push(match val1 + val2 {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '+'))
-                        });
-                    },
-                    Operation::Divide => {
-                        let val1 = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '/'))
-                        };
-                        let val2 = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '/'))
-                        };
-                        stack.push(match val2 / val1 {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '/'))
-                        });
-                    },
-                    Operation::Multiply => {
-                        let val1 = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '*'))
-                        };
-                        let val2 = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '*'))
-                        };
-                        stack.push(match val1 * val2 {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '*'))
-                        });
-                    },
-                    Operation::Subtract => {
-                        let val1 = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '-'))
-                        };
-                        let val2 = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '-'))
-                        };
-                        stack.push(match val2 - val1 {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '-'))
-                        });
-                    },
-                    Operation::Equal => {
-                        let val1 = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '='))
-                        };
-                        let val2 = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '='))
-                        };
-                        stack.push(Value::Boolean(val1 == val2));
-                    },
-                    Operation::NotEqual => {
-                        let val1 = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '!'))
-                        };
-                        let val2 = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '!'))
-                        };
-                        stack.push(Value::Boolean(val1 != val2));
-                    },
-                    Operation::Not => {
-                        let val1 = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '!')),
-                        };
-                        stack.push(match !val1 {
-                            Some(b) => b,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '!')),
-                        });
-                    },
-                    Operation::Lesser => {
-                        let val1 = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '<'))
-                        };
-                        let val2 = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '<'))
-                        };
-                        stack.push(Value::Boolean(val2 < val1));
-                    },
-                    Operation::LesserEqual => {
-                        let val1 = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '<'))
-                        };
-                        let val2 = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '<'))
-                        };
-                        stack.push(Value::Boolean(val2 <= val1));
-                    },
-                    Operation::Greater => {
-                        let val1 = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '>'))
-                        };
-                        let val2 = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '>'))
-                        };
-                        stack.push(Value::Boolean(val2 > val1));
-                    },
-                    Operation::GreaterEqual => {
-                        let val1 = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '>'))
-                        };
-                        let val2 = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '>'))
-                        };
-                        stack.push(Value::Boolean(val2 >= val1));
-                    },
-                    Operation::And => {
-                        let val1 = match stack.pop() {
-                            Some(v) => match v {
-                                Value::Boolean(b) => b,
-                                _ => return Err(RuntimeError::OperatorInvalidValues(token.pos, '&')),
-                            },
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '&'))
-                        };
-                        let val2 = match stack.pop() {
-                            Some(v) => match v {
-                                Value::Boolean(b) => b,
-                                _ => return Err(RuntimeError::OperatorInvalidValues(token.pos, '&')),
-                            },
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '&'))
-                        };
-                        stack.push(Value::Boolean(val1 && val2));
-                    },
-                    Operation::Or => {
-                        let val1 = match stack.pop() {
-                            Some(v) => match v {
-                                Value::Boolean(b) => b,
-                                _ => return Err(RuntimeError::OperatorInvalidValues(token.pos, '|')),
-                            },
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '|'))
-                        };
-                        let val2 = match stack.pop() {
-                            Some(v) => match v {
-                                Value::Boolean(b) => b,
-                                _ => return Err(RuntimeError::OperatorInvalidValues(token.pos, '|')),
-                            },
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '|'))
-                        };
-                        stack.push(Value::Boolean(val1 || val2));
-                    },
-                    Operation::Run => {
-                        let val1 = match stack.pop() {
-                            Some(v) => match v {
-                                Value::Block(b) => b,
-                                _ => return Err(RuntimeError::OperatorInvalidValues(token.pos, '$')),
-                            },
-                            None => return Err(RuntimeError::OperatorInvalidValues(token.pos, '$'))
-                        };
-                        execute(&val1, stack, function_table)?;
-                    },
-                }
-            },
-            TokenType::Keyword(keyword) => {
-                match keyword {
-                    Keyword::PRINT => print!("{}", stack.pop().unwrap_or(Value::String("".to_string()))),
-                    Keyword::EXIT => return Ok(()),
-                    Keyword::LOOP => {
-                        let function = match stack.pop() {
-                            Some(v) => match v {
-                                Value::Block(b) => b,
-                                _ => return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::LOOP)),
-                            },
-                            None => return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::LOOP))
-                        };
-
-                        loop {
-                            let condition = match stack.pop() {
-                                Some(v) => match v {
-                                    Value::Boolean(b) => b,
-                                    _ => return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::LOOP)),
-                                },
-                                None => return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::LOOP))
-                            };
-                            if !condition {
+                TokenType::Op(op) => {
+                    let invalid = |mismatch: Mismatch| RuntimeError::OperatorInvalidValues(token.pos, op.clone(), mismatch);
+
+                    match op {
+                        Operation::Add => apply(stack, token.pos, invalid, "two numbers or two strings",
+                            |below, top| (top + below).map_err(|(top, below)| (below, top)))?,
+                        Operation::Divide => apply(stack, token.pos, invalid, "two numbers, or a string and an index in it",
+                            |below, top| below / top)?,
+                        Operation::Multiply => {
+                            let expected = "two numbers, or an int and a string";
+                            let ((below, below_origin), (top, top_origin)) = pop_two(stack, invalid, expected)?;
+                            //Repeating a string can allocate a lot at once, so check before doing it
+                            if let (Value::String(string), Value::Integer(times)) = (&top, &below)
+                                && let Some(max_memory) = context.max_memory
+                                && stack.bytes.saturating_add(string.len().saturating_mul(*times as usize)) > max_memory {
+                                return Err(RuntimeError::MemoryLimitExceeded(token.pos, max_memory));
+                            }
+                            match top * below {
+                                Ok(v) => stack.push(v, token.pos),
+                                Err((top, below)) => return Err(invalid(Mismatch::new(expected, &[(&below, below_origin), (&top, top_origin)]))),
+                            }
+                        },
+                        Operation::Subtract => apply(stack, token.pos, invalid, "two numbers",
+                            |below, top| below - top)?,
+                        Operation::Equal => apply(stack, token.pos, invalid, "two values",
+                            |below, top| Ok(Value::Boolean(below == top)))?,
+                        Operation::NotEqual => apply(stack, token.pos, invalid, "two values",
+                            |below, top| Ok(Value::Boolean(below != top)))?,
+                        Operation::Not => {
+                            let val = pop_as(stack, |v| !v, invalid, "a bool")?;
+                            stack.push(val, token.pos);
+                        },
+                        Operation::Lesser => apply(stack, token.pos, invalid, "two values",
+                            |below, top| Ok(Value::Boolean(below < top)))?,
+                        Operation::LesserEqual => apply(stack, token.pos, invalid, "two values",
+                            |below, top| Ok(Value::Boolean(below <= top)))?,
+                        Operation::Greater => apply(stack, token.pos, invalid, "two values",
+                            |below, top| Ok(Value::Boolean(below > top)))?,
+                        Operation::GreaterEqual => apply(stack, token.pos, invalid, "two values",
+                            |below, top| Ok(Value::Boolean(below >= top)))?,
+                        Operation::And => apply(stack, token.pos, invalid, "two bools",
+                            |below, top| match (below, top) {
+                                (Value::Boolean(b1), Value::Boolean(b2)) => Ok(Value::Boolean(b1 && b2)),
+                                operands => Err(operands),
+                            })?,
+                        Operation::Or => apply(stack, token.pos, invalid, "two bools",
+                            |below, top| match (below, top) {
+                                (Value::Boolean(b1), Value::Boolean(b2)) => Ok(Value::Boolean(b1 || b2)),
+                                operands => Err(operands),
+                            })?,
+                        Operation::Run => {
+                            let (body, name) = pop_body(stack, function_table, token.pos, invalid, "a block or function")?;
+                            if is_tail {
+                                trace_after(token, stack, context);
+                                tail_call = Some((body, name, token.pos));
                                 break;
                             }
-                            execute(&function, stack, function_table)?;
-                        }
-                        
-                        
+                            return Ok(Some(Call {body: Rc::new(body), name, pos: token.pos, then: Then::Finish}));
+                        },
+                    }
+                },
+                TokenType::Keyword(keyword) => {
+                    let invalid = |mismatch: Mismatch| RuntimeError::KeywordInvalidValues(token.pos, keyword.clone(), mismatch);
 
-                    },
-                    Keyword::DUPLICATE => {
-                        let val = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::DUPLICATE))
-                        };
-                        stack.push(val.clone());
-                        stack.push(val);
-                    },
-                    Keyword::DROP => {
-                        stack.pop();
-                    },
-                    Keyword::SWAP => {
-                        if stack.data.len() < 2 {
-                            return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::SWAP));
-                        }
-                        let a = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::SWAP)),
-                        };
-                        let b = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::SWAP)),
-                        };
-                        stack.push(b);
-                        stack.push(a);
-                    },
-                    Keyword::DEPTH => {
-                        stack.push(Value::Integer(stack.data.len() as i32));
-                    },
-                    Keyword::ROT => {
-                        let len = stack.data.len();
-                        if len < 3 {
-                            return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::ROT));
-                        }
-                        stack.data.swap(len - 1, len - 2);
-                        stack.data.swap(len - 3, len - 1);
-                    },
-                    Keyword::NROT => {
-                        let len = stack.data.len();
-                        if len < 3 {
-                            return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::NROT));
-                        }
-                        stack.data.swap(len - 3, len - 1);
-                        stack.data.swap(len - 2, len - 1);
-                    },
-                    Keyword::OVER => {
-                        let len = stack.data.len();
-                        if len < 2 {
-                            return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::OVER));
-                        }
-                        let val = stack.data[len - 2].clone();
-                        stack.push(val);
-                    },
-                    Keyword::TUCK =>  {
-                        let len = stack.data.len();
-                        if len < 2 {
-                            return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::TUCK));
-                        }
-                        let val = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::TUCK))
-                        };
-                        stack.data.insert(len - 2, val.clone());
-                        stack.push(val);
-                    },
-                    Keyword::PICK => {
-                        let index = match stack.pop() {
-                            Some(v) => match v {
-                                Value::Integer(i) => i-1,
-                                _ => return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::PICK))
-                            },
-                            None => return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::PICK))
-                        };
-                        if index < 0 {
-                            return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::ROLL));
-                        }
-                        let index = index as usize;
-                        if stack.data.len() <= index {
-                            return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::PICK));
-                        }
-                        let val = stack.data[index].clone();
-                        stack.push(val);
-                    },
-                    Keyword::ROLL => {
-                        let index = match stack.pop() {
-                            Some(v) => match v {
-                                Value::Integer(i) => i-1,
-                                _ => return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::ROLL))
-                            },
-                            None => return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::ROLL))
-                        };
-                        if index < 0 {
-                            return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::ROLL));
-                        }
-                        let index = index as usize;
-                        if stack.data.len() <= index {
-                            return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::ROLL));
-                        }
-                        let val = stack.data.remove(index);
-                        stack.push(val);
-                    },
-                    Keyword::CLEAR => {
-                        stack.data.clear();
-                    },
-                    Keyword::GATE => {
-                        let true_func = match stack.pop() {
-                            Some(v) => match v {
-                                Value::Block(b) => b,
-                                _ => return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::GATE)),
-                            },
-                            None => return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::GATE))
-                        };
-                        let mut false_func = Vec::new();
-                        let cond;
-
-                        match stack.pop() {
-                            Some(v) => match v {
-                                Value::Block(b) => {
-                                    false_func = b;
-                                    match stack.pop() {
-                                        Some(v) => match v {
-                                            Value::Boolean(b) => cond = b,
-                                            _ => return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::GATE)),
-                                        },
-                                        None => return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::GATE))
-                                    }
-                                },
-                                Value::Boolean(boolean) => cond = boolean,
-                                _ => return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::GATE)),
-                            },
-                            None => return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::GATE))
-                        }
-                        
-                        if cond {
-                            execute(&true_func, stack, function_table)?;
-                        } else {
-                            execute(&false_func, stack, function_table)?;
-                        }
-                    },
-                    Keyword::TYPE => {
-                        let val = match stack.pop() {
-                            Some(v) => v,
-                            None => return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::TYPE)),
-                        };
-                        stack.push(Value::Tag(
-                            match val {
-                                Value::Block(_) => "block".to_string(),
-                                Value::Integer(_) => "int".to_string(),
-                                Value::Float(_) => "float".to_string(),
-                                Value::String(_) => "string".to_string(),
-                                Value::Boolean(_) => "bool".to_string(),
-                                Value::Tag(t) => t,
-                                Value::Function(_) => "function".to_string(), //This one should be impossible (for now) because functions turn into blocks when pushed
+                    match keyword {
+                        Keyword::PRINT => {
+                            context.require(Capability::Stdout, token.pos)?;
+                            print!("{}", stack.pop().unwrap_or(Value::String("".to_string())))
+                        },
+                        Keyword::EXIT => {
+                            trace_after(token, stack, context);
+                            break;
+                        },
+                        Keyword::LOOP => {
+                            let (function, name) = pop_body(stack, function_table, token.pos, invalid, "a block on top")?;
+
+                            if let Some(call) = next_cycle(Rc::new(function), name, token, stack, context, "a bool below the block")? {
+                                return Ok(Some(call));
+                            }
+                        },
+                        Keyword::DUPLICATE => {
+                            let (val, origin) = pop_value(stack, invalid, "a value")?;
+                            stack.push(val.clone(), origin);
+                            stack.push(val, origin);
+                        },
+                        Keyword::DROP => {
+                            stack.pop();
+                        },
+                        Keyword::SWAP => {
+                            if stack.data.len() < 2 {
+                                return Err(invalid(Mismatch::too_few("two values", stack)));
+                            }
+                            let (a, a_origin) = pop_value(stack, invalid, "two values")?;
+                            let (b, b_origin) = pop_value(stack, invalid, "two values")?;
+                            stack.push(b, b_origin);
+                            stack.push(a, a_origin);
+                        },
+                        Keyword::DEPTH => {
+                            stack.push(Value::Integer(stack.data.len() as i32), token.pos);
+                        },
+                        Keyword::ROT => {
+                            let len = stack.data.len();
+                            if len < 3 {
+                                return Err(invalid(Mismatch::too_few("three values", stack)));
+                            }
+                            stack.data.swap(len - 1, len - 2);
+                            stack.data.swap(len - 3, len - 1);
+                        },
+                        Keyword::NROT => {
+                            let len = stack.data.len();
+                            if len < 3 {
+                                return Err(invalid(Mismatch::too_few("three values", stack)));
+                            }
+                            stack.data.swap(len - 3, len - 1);
+                            stack.data.swap(len - 2, len - 1);
+                        },
+                        Keyword::OVER => {
+                            let len = stack.data.len();
+                            if len < 2 {
+                                return Err(invalid(Mismatch::too_few("two values", stack)));
+                            }
+                            let (val, origin) = stack.data[len - 2].clone();
+                            stack.push(val, origin);
+                        },
+                        Keyword::TUCK =>  {
+                            let len = stack.data.len();
+                            if len < 2 {
+                                return Err(invalid(Mismatch::too_few("two values", stack)));
+                            }
+                            let (val, origin) = pop_value(stack, invalid, "two values")?;
+                            stack.insert(len - 2, val.clone(), origin);
+                            stack.push(val, origin);
+                        },
+                        Keyword::PICK | Keyword::ROLL => {
+                            let (index, origin) = pop_value(stack, invalid, "an int index")?;
+                            let index = match index {
+                                Value::Integer(i) => i,
+                                v => return Err(invalid(Mismatch::new("an int index", &[(&v, origin)]))),
+                            };
+                            if index < 1 || stack.data.len() < index as usize {
+                                return Err(invalid(Mismatch {expected: "an index within the stack", found: vec![(format!("index {}", index), origin)]}));
+                            }
+                            let index = index as usize - 1;
+                            let (val, origin) = if let Keyword::PICK = keyword {
+                                stack.data[index].clone()
+                            } else {
+                                stack.remove(index)
+                            };
+                            stack.push(val, origin);
+                        },
+                        Keyword::CLEAR => {
+                            stack.clear();
+                        },
+                        Keyword::GATE => {
+                            let (true_func, true_name) = pop_body(stack, function_table, token.pos, invalid, "a block on top")?;
+
+                            let expected = "a bool, or another block, below the block";
+                            let (cond, false_func) = match pop_value(stack, invalid, expected)? {
+                                (v @ (Value::Block(_) | Value::Function(_)), origin) =>
+                                    (pop_as(stack, into_bool, invalid, "a bool below the two blocks")?, Some((v, origin))),
+                                (Value::Boolean(boolean), _) => (boolean, None),
+                                (v, origin) => return Err(invalid(Mismatch::new(expected, &[(&v, origin)]))),
+                            };
+                            if let Some(tracer) = &context.tracer {
+                                tracer.borrow_mut().branch(token.pos, cond);
                             }
-                        ));
-                    },
-                    Keyword::INPUT => {
-                        let mut input = String::new();
-                        if let Err(_) = std::io::stdin().read_line(&mut input) {
-                            return Err(RuntimeError::InputError(token.pos));
-                        };
-                        stack.push(Value::String(input.trim().to_string()));
-                    },
-                    Keyword::STRLEN => {
-                        let string = match stack.pop() {
-                            Some(v) => match v {
-                                Value::String(s) => s,
-                                _ => return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::STRLEN)),
-                            },
-                            None => return Err(RuntimeError::KeywordInvalidValues(token.pos, Keyword::STRLEN))
-                        };
                         
-                        stack.push(Value::Integer(string.chars().count() as i32))
+                            let (branch, name) = if cond {
+                                (true_func, true_name)
+                            } else if let Some(false_func) = false_func {
+                                into_body(false_func, function_table, token.pos, invalid, expected)?
+                            } else {
+                                trace_after(token, stack, context);
+                                frame.next += 1;
+                                continue;
+                            };
+                            if is_tail {
+                                trace_after(token, stack, context);
+                                tail_call = Some((branch, name, token.pos));
+                                break;
+                            }
+                            return Ok(Some(Call {body: Rc::new(branch), name, pos: token.pos, then: Then::Finish}));
+                        },
+                        Keyword::TYPE => {
+                            let (val, _) = pop_value(stack, invalid, "a value")?;
+                            stack.push(Value::Tag(val.type_name().to_string()), token.pos);
+                        },
+                        Keyword::INPUT => {
+                            context.require(Capability::Stdin, token.pos)?;
+                            let mut input = String::new();
+                            if let Err(_) = std::io::stdin().read_line(&mut input) {
+                                return Err(RuntimeError::InputError(token.pos));
+                            };
+                            stack.push(Value::String(input.trim().to_string()), token.pos);
+                        },
+                        Keyword::STRLEN => {
+                            let string = pop_as(stack, into_string, invalid, "a string")?;
+                            stack.push(Value::Integer(string.chars().count() as i32), token.pos)
+                        },
+                        Keyword::ASSERT => {
+                            //The message is optional, so a string on top means the condition is below it
+                            let expected = "a bool, or a bool and a message";
+                            let (condition, message) = match pop_value(stack, invalid, expected)? {
+                                (Value::Boolean(b), _) => (b, None),
+                                (Value::String(message), _) => (pop_as(stack, into_bool, invalid, "a bool below the message")?, Some(message)),
+                                (v, origin) => return Err(invalid(Mismatch::new(expected, &[(&v, origin)]))),
+                            };
+                            if !condition {
+                                return Err(RuntimeError::AssertionFailed(token.pos, message));
+                            }
+                        },
+                        Keyword::READ => {
+                            context.require(Capability::FileRead, token.pos)?;
+                            let path = pop_as(stack, into_string, invalid, "a file path")?;
+                            match std::fs::read_to_string(&path) {
+                                Ok(content) => stack.push(Value::String(content), token.pos),
+                                Err(e) => return Err(RuntimeError::FileError(token.pos, path, e)),
+                            }
+                        },
+                        Keyword::READLINES => {
+                            context.require(Capability::FileRead, token.pos)?;
+                            let path = pop_as(stack, into_string, invalid, "a file path")?;
+                            let content = match std::fs::read_to_string(&path) {
+                                Ok(content) => content,
+                                Err(e) => return Err(RuntimeError::FileError(token.pos, path, e)),
+                            };
+                            let mut count = 0;
+                            for line in content.lines() {
+                                stack.push(Value::String(line.to_string()), token.pos);
+                                count += 1;
+                            }
+                            stack.push(Value::Integer(count), token.pos);
+                        },
+                        Keyword::WRITE | Keyword::APPEND => {
+                            context.require(Capability::FileWrite, token.pos)?;
+                            let path = pop_as(stack, into_string, invalid, "a file path")?;
+                            let content = pop_as(stack, into_string, invalid, "a string below the file path")?;
+                            let result = if let Keyword::APPEND = keyword {
+                                std::fs::OpenOptions::new().append(true).create(true).open(&path)
+                                    .and_then(|mut file| file.write_all(content.as_bytes()))
+                            } else {
+                                std::fs::write(&path, content)
+                            };
+                            if let Err(e) = result {
+                                return Err(RuntimeError::FileError(token.pos, path, e));
+                            }
+                        },
+                        Keyword::EXISTS => {
+                            context.require(Capability::FileRead, token.pos)?;
+                            let path = pop_as(stack, into_string, invalid, "a file path")?;
+                            match std::fs::exists(&path) {
+                                Ok(exists) => stack.push(Value::Boolean(exists), token.pos),
+                                Err(e) => return Err(RuntimeError::FileError(token.pos, path, e)),
+                            }
+                        },
+                        Keyword::DELETE => {
+                            context.require(Capability::FileWrite, token.pos)?;
+                            let path = pop_as(stack, into_string, invalid, "a file path")?;
+                            if let Err(e) = std::fs::remove_file(&path) {
+                                return Err(RuntimeError::FileError(token.pos, path, e));
+                            }
+                        },
+                        Keyword::ARGS => {
+                            for arg in &context.args {
+                                stack.push(Value::String(arg.clone()), token.pos);
+                            }
+                            stack.push(Value::Integer(context.args.len() as i32), token.pos);
+                        },
+                        Keyword::ENV => {
+                            context.require(Capability::Env, token.pos)?;
+                            let name = pop_as(stack, into_string, invalid, "a variable name")?;
+                            //Unset variables push false, so they can be told apart from empty ones
+                            match std::env::var(&name) {
+                                Ok(value) => stack.push(Value::String(value), token.pos),
+                                Err(_) => stack.push(Value::Boolean(false), token.pos),
+                            }
+                        },
+                        Keyword::TRY => {
+                            let (handler, handler_name) = pop_body(stack, function_table, token.pos, invalid, "a handler block on top")?;
+                            let (body, name) = pop_body(stack, function_table, token.pos, invalid, "a block below the handler")?;
+                            return Ok(Some(Call {body: Rc::new(body), name, pos: token.pos, then: Then::Try(Rc::new(handler), handler_name)}));
+                        },
+                        Keyword::BREAK => if let Some(tracer) = &context.tracer {
+                            tracer.borrow_mut().interrupt(token.pos);
+                        },
+                        _ => ()//unused keywords,
                     }
-                    _ => ()//unused keywords,
+                },
+            }
+            //println!("{:#?}", stack);
+            trace_after(token, stack, context);
+            frame.next += 1;
+        }
+
+        match tail_call {
+            Some((body, name, pos)) => {
+                if let Some(tracer) = &context.tracer {
+                    tracer.borrow_mut().enter(name, pos, true);
                 }
+                frame.code = Rc::new(body);
+                frame.next = 0;
+                frame.called_at = Some(pos);
             },
+            None => return Ok(None),
         }
-        //println!("{:#?}", stack);
     }
+}
+
+/// Checks the top frame's declared stack effects and returns from it to the token that called it
+/// 
+/// **Returns:** The call that token makes next, if it is a `loop` with another cycle to run
+fn return_from<'a>(frames: &mut Vec<Frame<'a>>, stack: &mut Stack, context: &mut Context) -> Result<Option<Call<'a>>, RuntimeError> {
+    if let Some(frame) = frames.last() {
+        for contract in frame.contracts.iter().rev() {
+            leave(stack, contract)?;
+        }
+    }
+    let Some(frame) = frames.pop() else {
+        return Ok(None);
+    };
+    if frames.is_empty() {
+        leave_root(&frame, context);
+        return Ok(None);
+    }
+    let then = frame.then;
+    leave_frame(context);
+    let Some(caller) = frames.last_mut() else {
+        return Ok(None);
+    };
+    let code = Rc::clone(&caller.code);
+    let token = &code[caller.next];
+    if let Then::Loop(body, name) = then
+        && let Some(call) = next_cycle(body, name, token, stack, context, "the block to leave a bool on top")? {
+        return Ok(Some(call));
+    }
+    trace_after(token, stack, context);
+    caller.next += 1;
+    Ok(None)
+}
+
+/// Pops frames until a `try` catches the error
+/// 
+/// **Returns:** The call to the handler, or the error if nothing catches it
+fn unwind<'a>(frames: &mut Vec<Frame<'a>>, error: RuntimeError, stack: &mut Stack, context: &mut Context) -> Result<Option<Call<'a>>, RuntimeError> {
+    while let Some(frame) = frames.pop() {
+        let Some(caller) = frames.last() else {
+            leave_root(&frame, context);
+            break;
+        };
+        leave_frame(context);
+        let Then::Try(handler, handler_name) = frame.then else {
+            continue;
+        };
+        //The handler gets the error's message
+        let message = match &error {
+            RuntimeError::FileError(_, path, e) => format!("{}: {}", path, e),
+            RuntimeError::InputError(_) => "Issue with getting user input.".to_string(),
+            _ => continue,
+        };
+        let pos = caller.code[caller.next].pos;
+        stack.push(Value::String(message), pos);
+        return Ok(Some(Call {body: handler, name: handler_name, pos, then: Then::Finish}));
+    }
+    Err(error)
+}
 
+/// Starts running a block or function in a new frame
+fn push_call<'a>(frames: &mut Vec<Frame<'a>>, call: Call<'a>, context: &mut Context) -> Result<(), RuntimeError> {
+    if context.depth >= context.max_call_depth {
+        return Err(RuntimeError::StackOverflow(call.pos, context.max_call_depth));
+    }
+    context.depth += 1;
+    if let Some(tracer) = &context.tracer {
+        tracer.borrow_mut().enter(call.name, call.pos, false);
+    }
+    frames.push(Frame {code: call.body, next: 0, called_at: Some(call.pos), contracts: Vec::new(), then: call.then});
     Ok(())
+}
+
+/// Tells the tracer, if there is one, that a frame has returned
+fn leave_frame(context: &mut Context) {
+    if let Some(tracer) = &context.tracer {
+        tracer.borrow_mut().leave();
+    }
+    context.depth -= 1;
+}
+
+/// Tells the tracer that the code run by `execute` has returned, if a tail call started a frame for it
+fn leave_root(frame: &Frame, context: &mut Context) {
+    if frame.called_at.is_some() && let Some(tracer) = &context.tracer {
+        tracer.borrow_mut().leave();
+    }
+}
+
+/// Pops a `loop` condition and, if it is `true`, starts another cycle
+/// 
+/// **Arguments:**
+/// * `body`: The body of the loop
+/// * `name`: The name of the function being looped, or `None` for a block
+/// * `token`: The `loop` keyword
+/// * `expected`: What should be on the stack, for the error if the condition is missing
+fn next_cycle<'a>(body: Rc<Cow<'a, [Token]>>, name: Option<&'a str>, token: &Token, stack: &mut Stack, context: &mut Context, expected: &'static str) -> Result<Option<Call<'a>>, RuntimeError> {
+    let invalid = |mismatch: Mismatch| RuntimeError::KeywordInvalidValues(token.pos, Keyword::LOOP, mismatch);
+    if !pop_as(stack, into_bool, invalid, expected)? {
+        return Ok(None);
+    }
+    //An empty body runs no tokens, so the cycle itself has to count
+    context.step(stack, token.pos)?;
+    Ok(Some(Call {body: Rc::clone(&body), name, pos: token.pos, then: Then::Loop(body, name)}))
+}
+
+/// Tells the tracer, if there is one, that a token has finished
+fn trace_after(token: &Token, stack: &Stack, context: &Context) {
+    if let Some(tracer) = &context.tracer {
+        tracer.borrow_mut().after(&token.kind, token.pos, stack);
+    }
 }
\ No newline at end of file